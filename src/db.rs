@@ -1,7 +1,17 @@
+use bincode::Options;
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, marker::PhantomData, path::PathBuf};
+use sled::{
+    transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionError},
+    Transactional,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
 
 use super::error::*;
+use super::pack::*;
 use super::structs::*;
 use super::traits::*;
 
@@ -11,22 +21,91 @@ pub struct ChunkDbState {
     path_gen: FilePathGen,
 }
 
+/// Replaces the value of `key` by the one `update` computes from the current value
+///
+/// `update` returns the new value, `None` removes the entry, and the result of the operation.
+/// The value is swapped atomically, if it was changed in the meantime the update is computed
+/// again, so an entry is never lost and concurrent updates are not overwritten.
+fn update_entry<R>(
+    tree: &sled::Tree,
+    key: &Hash256,
+    mut update: impl FnMut(Option<&[u8]>) -> Result<(Option<Vec<u8>>, R)>,
+) -> Result<R> {
+    loop {
+        let old = tree.get(key)?;
+        let (new, result) = update(old.as_deref())?;
+        if tree.compare_and_swap(key, old, new)?.is_ok() {
+            return Ok(result);
+        }
+    }
+}
+
+/// Aborts a transaction with the error of `result`
+fn abort<T>(result: Result<T>) -> ConflictableTransactionResult<T, Error> {
+    result.map_err(ConflictableTransactionError::Abort)
+}
+
+impl CanonicalEncode for ChunkDbState {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        self.unused_paths.encode_canonical(out);
+        self.path_gen.encode_canonical(out);
+    }
+}
+
 #[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
 struct ChunkDbEntry {
     ref_count: RefCount,
     file_name: PathBuf,
+    /// Position in the pack `file_name`, `None` if the chunk has a file of its own
+    range: Option<PackRange>,
+}
+
+/// Entry written before chunks were packed, every chunk has a file of its own
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct LegacyChunkDbEntry {
+    ref_count: RefCount,
+    file_name: PathBuf,
+}
+
+impl ChunkDbEntry {
+    fn decode(data: &[u8]) -> Result<Self> {
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes();
+
+        // legacy entries end right after the file name, so they never decode as current ones
+        match options.deserialize::<ChunkDbEntry>(data) {
+            Ok(entry) => Ok(entry),
+            Err(_) => {
+                let legacy = options.deserialize::<LegacyChunkDbEntry>(data)?;
+                Ok(ChunkDbEntry {
+                    ref_count: legacy.ref_count,
+                    file_name: legacy.file_name,
+                    range: None,
+                })
+            }
+        }
+    }
+
+    fn location(&self) -> ChunkLocation {
+        ChunkLocation {
+            file_name: self.file_name.clone(),
+            range: self.range,
+        }
+    }
 }
 
 impl Encrypt for ChunkDbEntry {}
 
-/// ChunkDb manages mappings from chunk hashes to file names
+/// ChunkDb manages mappings from chunk hashes to the locations of the stored chunks
 ///
-/// The backuped chunks are supposed to be encrypted and stored under the filenames provided by this
+/// The backuped chunks are supposed to be encrypted and stored in pack files or under file names
+/// provided by this
 #[derive(Debug)]
 pub struct ChunkDb {
     pub(crate) chunk_map: sled::Tree,
     pub(crate) state: ChunkDbState,
-    pub(crate) chunk_enc_key: Key256,
+    pub(crate) chunk_enc_key: Locked<Key256>,
 }
 
 impl ChunkDb {
@@ -40,7 +119,7 @@ impl ChunkDb {
             if key.len() != HASH_SIZE {
                 return Err(BackrubError::SledKeyLengthError.into());
             }
-            let _: Hash256 = key
+            let key: Hash256 = key
                 .chunks_exact(HASH_SIZE)
                 .next()
                 .map_or_else(
@@ -49,28 +128,83 @@ impl ChunkDb {
                 )?
                 .try_into()?;
             // Check data
-            let _ = ChunkDbEntry::decrypt(&encrypted_data, &self.chunk_enc_key)?;
+            let _ = self.decrypt_entry(&key, &encrypted_data)?;
         }
         Ok(())
     }
 
     /// Restores a saved ChunkDb
     ///
+    /// The state is saved in the manifest after the tree was changed, so it is older than the
+    /// tree if the manifest could not be written. It is rebuilt like [`Self::rebuild`] does if it
+    /// would hand out a file name the tree references.
+    ///
     /// Returns an [`Error`] when the [`Self::self_test()`] fails
     pub fn restore(
         tree: sled::Tree,
         chunk_enc_key: Key256,
         state: ChunkDbState,
     ) -> Result<ChunkDb> {
-        let cs = ChunkDb {
+        let mut cs = ChunkDb {
             state: state,
             chunk_map: tree,
-            chunk_enc_key: chunk_enc_key,
+            chunk_enc_key: Locked::new(chunk_enc_key),
         };
         cs.self_test()?;
+
+        let (used, last) = cs.used_file_names()?;
+        if cs.state.path_gen.0 < last
+            || cs
+                .state
+                .unused_paths
+                .iter()
+                .any(|file_name| used.contains(file_name))
+        {
+            cs.rebuild_state(&used, last);
+        }
         Ok(cs)
     }
 
+    /// Restores a ChunkDb whose state was lost together with the manifest
+    ///
+    /// File names are generated in order, so the generator continues after the last used file
+    /// name and all unused ones before it can be reused.
+    pub fn rebuild(tree: sled::Tree, chunk_enc_key: Key256) -> Result<ChunkDb> {
+        // an empty state is behind every used file name, so restoring rebuilds it
+        Self::restore(
+            tree,
+            chunk_enc_key,
+            ChunkDbState {
+                path_gen: FilePathGen::default(),
+                unused_paths: Vec::<PathBuf>::default(),
+            },
+        )
+    }
+
+    /// Returns the file names referenced by the tree and the position of the last generated one
+    fn used_file_names(&self) -> Result<(BTreeSet<PathBuf>, u64)> {
+        let used: BTreeSet<PathBuf> = self
+            .get_mappings()?
+            .into_values()
+            .map(|(_, location)| location.file_name)
+            .collect();
+        let last = used
+            .iter()
+            .filter_map(|file_name| FilePathGen::position(file_name))
+            .max()
+            .unwrap_or_default();
+        Ok((used, last))
+    }
+
+    fn rebuild_state(&mut self, used: &BTreeSet<PathBuf>, last: u64) {
+        self.state.unused_paths = FilePathGen::default()
+            .take(last as usize)
+            .map(PathBuf::from)
+            .filter(|file_name| !used.contains(file_name))
+            .collect();
+        self.state.path_gen = FilePathGen::from(last);
+    }
+
     /// Creates a new **empty** ChunkDb
     ///
     /// Returns [`BackrubError::SledTreeNotEmpty`] if provided tree is not empty
@@ -84,78 +218,197 @@ impl ChunkDb {
                 unused_paths: Vec::<PathBuf>::default(),
             },
             chunk_map: tree,
-            chunk_enc_key: chunk_enc_key,
+            chunk_enc_key: Locked::new(chunk_enc_key),
         };
         Ok(cs)
     }
 
-    /// Inserts a new [`Hash256`] into the database and returns a tuple [`(RefCount, PathBuf)`] of the reference count and the file name the chunk should be stored in
-    pub fn insert(&mut self, key: &Hash256) -> Result<(RefCount, PathBuf)> {
-        match self.chunk_map.remove(key)? {
-            None => {
-                let file_name = self.state.unused_paths
-                                         .pop()
-                                         .unwrap_or_else(||{
-                                             PathBuf::from(self.state.path_gen.next()
-                                                           .expect("BUG: Please contact me if you need more than 10^19 chunks, I'd really like to know the system you are on"))
-                    });
-
-                self.chunk_map.insert(
-                    key,
-                    ChunkDbEntry {
-                        file_name: file_name.clone(),
-                        ref_count: 1,
-                    }
-                    .encrypt(&self.chunk_enc_key)?,
-                )?;
+    fn encrypt_entry(&self, key: &Hash256, entry: &ChunkDbEntry) -> Result<Vec<u8>> {
+        entry.encrypt_with_aad(
+            &self.chunk_enc_key,
+            &associated_data(ObjectType::ChunkDbEntry, key),
+        )
+    }
 
-                Ok((1, file_name))
-            }
-            Some(old) => {
-                let old = ChunkDbEntry::decrypt(&old, &self.chunk_enc_key)?;
+    fn decrypt_entry(&self, key: &Hash256, encrypted_data: &[u8]) -> Result<ChunkDbEntry> {
+        let data = Vec::<u8>::decrypt_with_aad(
+            encrypted_data,
+            &self.chunk_enc_key,
+            &associated_data(ObjectType::ChunkDbEntry, key),
+        )?;
+        ChunkDbEntry::decode(&data)
+    }
+
+    fn write_entry(&self, key: &Hash256, entry: &ChunkDbEntry) -> Result<()> {
+        self.chunk_map
+            .insert(key, self.encrypt_entry(key, entry)?)?;
+        Ok(())
+    }
 
-                let ref_count = old.ref_count + 1;
+    /// Hands out a file name for a new pack or chunk file, reusing names of removed files first
+    pub fn new_file_name(&mut self) -> PathBuf {
+        self.state.unused_paths
+                  .pop()
+                  .unwrap_or_else(||{
+                      PathBuf::from(self.state.path_gen.next()
+                                    .expect("BUG: Please contact me if you need more than 10^19 chunk files, I'd really like to know the system you are on"))
+                  })
+    }
 
-                self.chunk_map.insert(
-                    key,
-                    ChunkDbEntry {
-                        file_name: old.file_name.clone(),
-                        ref_count,
-                    }
-                    .encrypt(&self.chunk_enc_key)?,
-                )?;
+    /// Makes the name of a removed file available to [`Self::new_file_name`] again
+    pub fn release_file_name(&mut self, file_name: PathBuf) {
+        self.state.unused_paths.push(file_name);
+    }
 
-                Ok((ref_count, old.file_name))
+    /// Returns `true` if `file_name` was released and will be handed out again
+    pub fn is_released_file_name(&self, file_name: &Path) -> bool {
+        self.state.unused_paths.iter().any(|p| p == file_name)
+    }
+
+    /// Adds a reference to a stored chunk and returns a tuple [`(RefCount, ChunkLocation)`] of the new reference count and the location of the chunk
+    ///
+    /// Returns `Ok(None)` if the chunk is not stored yet, it has to be written and added with [`Self::insert_new`]
+    pub fn insert(&mut self, key: &Hash256) -> Result<Option<(RefCount, ChunkLocation)>> {
+        update_entry(&self.chunk_map, key, |old| match old {
+            None => Ok((None, None)),
+            Some(old) => {
+                let mut entry = self.decrypt_entry(key, old)?;
+                entry.ref_count += 1;
+                Ok((
+                    Some(self.encrypt_entry(key, &entry)?),
+                    Some((entry.ref_count, entry.location())),
+                ))
             }
-        }
+        })
     }
 
-    /// Removes a chunk reference and returns the reference count as well as the file name the chunk is supposed to be stored in.
+    /// Adds a chunk that was just written to `location` with a single reference
+    pub fn insert_new(&mut self, key: &Hash256, location: ChunkLocation) -> Result<()> {
+        self.write_entry(
+            key,
+            &ChunkDbEntry {
+                ref_count: 1,
+                file_name: location.file_name,
+                range: location.range,
+            },
+        )
+    }
+
+    /// Records that a stored chunk was moved to `location`, the reference count is kept
+    pub fn relocate(&mut self, key: &Hash256, location: ChunkLocation) -> Result<()> {
+        update_entry(&self.chunk_map, key, |old| {
+            let old = old.ok_or(BackrubError::ChunkDidNotExist(*key))?;
+            let old = self.decrypt_entry(key, old)?;
+            let entry = ChunkDbEntry {
+                ref_count: old.ref_count,
+                file_name: location.file_name.clone(),
+                range: location.range,
+            };
+            Ok((Some(self.encrypt_entry(key, &entry)?), ()))
+        })
+    }
+
+    /// Removes a chunk reference and returns the reference count as well as the location the chunk is supposed to be stored at.
     ///
-    /// - Returns `Ok(None)` if chunk was not referenced (no file name is associated with that chunk hash)
-    /// - Returns `Ok((0, <file_name>))` if the last reference to this chunk was removed indicating that the chunk file should be removed
-    pub fn remove(&mut self, key: &Hash256) -> Result<Option<(RefCount, PathBuf)>> {
-        match self.chunk_map.remove(key)? {
-            None => Ok(None),
+    /// - Returns `Ok(None)` if chunk was not referenced (no location is associated with that chunk hash)
+    /// - Returns `Ok((0, <location>))` if the last reference to this chunk was removed indicating that an unpacked chunk file should be removed,
+    ///   the space of packed chunks is reclaimed by repacking
+    pub fn remove(&mut self, key: &Hash256) -> Result<Option<(RefCount, ChunkLocation)>> {
+        let removed = update_entry(&self.chunk_map, key, |old| match old {
+            None => Ok((None, None)),
             Some(old) => {
-                let old = ChunkDbEntry::decrypt(&old, &self.chunk_enc_key)?;
-                if old.ref_count <= 1 {
-                    // save old file name for reuse
-                    self.state.unused_paths.push(old.file_name.clone());
-                    Ok(Some((0, old.file_name)))
+                let mut entry = self.decrypt_entry(key, old)?;
+                if entry.ref_count <= 1 {
+                    Ok((None, Some((0, entry.location()))))
                 } else {
-                    let ref_count = old.ref_count - 1;
-                    self.chunk_map.insert(
-                        key,
-                        ChunkDbEntry {
-                            file_name: old.file_name.clone(),
-                            ref_count,
-                        }
-                        .encrypt(&self.chunk_enc_key)?,
-                    )?;
-                    Ok(Some((ref_count, old.file_name)))
+                    entry.ref_count -= 1;
+                    Ok((
+                        Some(self.encrypt_entry(key, &entry)?),
+                        Some((entry.ref_count, entry.location())),
+                    ))
                 }
             }
+        })?;
+
+        // save old file name for reuse, packs are still used by other chunks
+        if let Some((0, location)) = &removed {
+            if !location.is_packed() {
+                self.state.unused_paths.push(location.file_name.clone());
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Adds a reference to a stored chunk in `batch`, like [`Self::insert`] does
+    ///
+    /// Chunks that were added to `batch` with [`Self::insert_new_batched`] count as stored,
+    /// the returned reference count includes the changes of `batch`.
+    pub fn insert_batched(
+        &self,
+        batch: &mut RefCountBatch,
+        key: &Hash256,
+    ) -> Result<Option<(RefCount, ChunkLocation)>> {
+        let stored = self.get_entry(key)?;
+        let refs = batch.chunks.entry(*key).or_default();
+        let (ref_count, location) = match (stored, &refs.new) {
+            // a chunk that is relocated by the batch is read from its new location
+            (Some((ref_count, location)), _) => (
+                ref_count,
+                batch.relocations.get(key).cloned().unwrap_or(location),
+            ),
+            (None, Some(location)) => (0, location.clone()),
+            (None, None) => return Ok(None),
+        };
+        refs.added += 1;
+        Ok(Some((refs.apply(ref_count), location)))
+    }
+
+    /// Adds a chunk that was just written to `location` with a single reference in `batch`
+    pub fn insert_new_batched(
+        &self,
+        batch: &mut RefCountBatch,
+        key: &Hash256,
+        location: ChunkLocation,
+    ) {
+        let refs = batch.chunks.entry(*key).or_default();
+        refs.new = Some(location);
+        refs.added += 1;
+    }
+
+    /// Moves a stored chunk to `location` when `batch` is committed, like [`Self::relocate`] does
+    ///
+    /// The chunk has to be written to `location` before the batch is committed.
+    pub fn relocate_batched(
+        &self,
+        batch: &mut RefCountBatch,
+        key: &Hash256,
+        location: ChunkLocation,
+    ) {
+        batch.relocations.insert(*key, location);
+    }
+
+    /// Removes a chunk reference in `batch`, like [`Self::remove`] does
+    ///
+    /// The returned reference count includes the changes of `batch`, the released chunks are
+    /// returned by [`RefCountBatch::commit`].
+    pub fn remove_batched(
+        &self,
+        batch: &mut RefCountBatch,
+        key: &Hash256,
+    ) -> Result<Option<(RefCount, ChunkLocation)>> {
+        let stored = self.get_entry(key)?;
+        let refs = batch.chunks.entry(*key).or_default();
+        let (ref_count, location) = match (stored, &refs.new) {
+            (Some(entry), _) => entry,
+            (None, Some(location)) => (0, location.clone()),
+            (None, None) => return Ok(None),
+        };
+        match refs.apply(ref_count) {
+            0 => Ok(None),
+            ref_count => {
+                refs.removed += 1;
+                Ok(Some((ref_count - 1, location)))
+            }
         }
     }
 
@@ -169,8 +422,8 @@ impl ChunkDb {
     /// Returns a BTreeMap containing the contens of the internal mappings.
     ///
     /// This decrypts all contens creates a compleatly new map in memory
-    pub fn get_mappings(&self) -> Result<BTreeMap<Hash256, (RefCount, PathBuf)>> {
-        let mut result = BTreeMap::<Hash256, (RefCount, PathBuf)>::new();
+    pub fn get_mappings(&self) -> Result<BTreeMap<Hash256, (RefCount, ChunkLocation)>> {
+        let mut result = BTreeMap::<Hash256, (RefCount, ChunkLocation)>::new();
         for data in self.chunk_map.iter() {
             let (key, encrypted_data) = data?;
             if key.len() != HASH_SIZE {
@@ -184,19 +437,19 @@ impl ChunkDb {
                         Ok,
                     )?
                     .try_into()?;
-                let chunk_file = ChunkDbEntry::decrypt(&encrypted_data, &self.chunk_enc_key)?;
-                result.insert(key, (chunk_file.ref_count, chunk_file.file_name));
+                let chunk_file = self.decrypt_entry(&key, &encrypted_data)?;
+                result.insert(key, (chunk_file.ref_count, chunk_file.location()));
             }
         }
         Ok(result)
     }
 
-    pub fn get_entry(&self, key: &Hash256) -> Result<Option<(RefCount, PathBuf)>> {
+    pub fn get_entry(&self, key: &Hash256) -> Result<Option<(RefCount, ChunkLocation)>> {
         match self.chunk_map.get(key)? {
             None => Ok(None),
             Some(encrypted_data) => {
-                let chunk_file = ChunkDbEntry::decrypt(&encrypted_data, &self.chunk_enc_key)?;
-                Ok(Some((chunk_file.ref_count, chunk_file.file_name)))
+                let chunk_file = self.decrypt_entry(key, &encrypted_data)?;
+                Ok(Some((chunk_file.ref_count, chunk_file.location())))
             }
         }
     }
@@ -204,48 +457,51 @@ impl ChunkDb {
     pub fn get_ref_count(&self, key: &Hash256) -> Result<Option<RefCount>> {
         match self.get_entry(key)? {
             None => Ok(None),
-            Some((ref_count, _file_name)) => Ok(Some(ref_count)),
+            Some((ref_count, _location)) => Ok(Some(ref_count)),
         }
     }
 
-    pub fn get_file_name(&self, key: &Hash256) -> Result<Option<PathBuf>> {
+    pub fn get_location(&self, key: &Hash256) -> Result<Option<ChunkLocation>> {
         match self.get_entry(key)? {
             None => Ok(None),
-            Some((_ref_count, file_name)) => Ok(Some(file_name)),
+            Some((_ref_count, location)) => Ok(Some(location)),
         }
     }
 }
 
 #[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
-struct RcDbEntry<T> {
-    data: T,
+struct InodeDbEntry {
+    inode: Inode,
     ref_count: RefCount,
 }
 
-impl<T: Serialize + for<'a> Deserialize<'a>> Encrypt for RcDbEntry<T> {}
+impl Encrypt for InodeDbEntry {}
 
-impl<T: Hashable> Hashable for RcDbEntry<T> {
-    fn hash(&self) -> Result<blake3::Hash> {
-        self.data.hash()
-    }
-    fn keyed_hash(&self, key: &Key256) -> Result<blake3::Hash> {
-        self.data.keyed_hash(key)
-    }
+/// Entry of a write-only repository, the inode is sealed so write-only clients can not read it
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct SealedInodeDbEntry {
+    inode: Vec<u8>,
+    ref_count: RefCount,
+}
+
+impl Encrypt for SealedInodeDbEntry {}
+
+/// Inode of an entry, as far as it could be decrypted
+#[derive(Clone, Debug)]
+enum InodeData {
+    Plain(Inode),
+    Sealed(Vec<u8>),
 }
 
-/// Generic encrypted reference countig database with [sled] backend
 #[derive(Debug)]
-pub struct RcDb<T: Hashable + Serialize + for<'a> Deserialize<'a>> {
+pub struct InodeDb {
     tree: sled::Tree,
-    data_enc_key: Key256,
-    data_hash_key: Key256,
-    entry_type: PhantomData<T>,
+    inode_enc_key: Locked<Key256>,
+    inode_hash_key: Locked<Key256>,
+    sealing: Option<SealingKeys>,
 }
 
-impl<T: Clone + Hashable + Serialize + for<'a> Deserialize<'a>> RcDb<T> {
-    /// Database self test
-    ///
-    /// This performs a complete table scan checking all keys and integrity of all data
+impl InodeDb {
     pub fn self_test(&self) -> Result<()> {
         for data in self.tree.iter() {
             let (key, encrypted_data) = data?;
@@ -263,124 +519,209 @@ impl<T: Clone + Hashable + Serialize + for<'a> Deserialize<'a>> RcDb<T> {
                 )?
                 .try_into()?;
 
-            // Check data
-            let entry = RcDbEntry::<T>::decrypt(&encrypted_data, &self.data_enc_key)?;
-            if key != Hash256::from(*entry.data.keyed_hash(&self.data_hash_key)?.as_bytes()) {
-                return Err(BackrubError::SelfTestError.into());
+            // Check data, write-only clients can only check the entry but not the inode in it
+            let (_ref_count, data) = self.decrypt_entry(&key, &encrypted_data)?;
+            if self.sealing.as_ref().is_none_or(SealingKeys::can_open) {
+                let inode = self.open_inode(&key, &data)?;
+                if key != Hash256::from(*inode.keyed_hash(&self.inode_hash_key)?.as_bytes()) {
+                    return Err(BackrubError::SelfTestError.into());
+                }
             }
         }
         Ok(())
     }
 
-    /// Creates a new reference counting database from a `sled::Tree` and running a self test
-    pub fn new(tree: sled::Tree, data_enc_key: Key256, data_hash_key: Key256) -> Result<RcDb<T>> {
-        let db = RcDb {
+    pub fn new(tree: sled::Tree, inode_enc_key: Key256, inode_hash_key: Key256) -> Result<InodeDb> {
+        Self::with_sealing(tree, inode_enc_key, inode_hash_key, None)
+    }
+
+    /// Creates an InodeDb whose inodes are sealed with `sealing` if it is given
+    pub fn with_sealing(
+        tree: sled::Tree,
+        inode_enc_key: Key256,
+        inode_hash_key: Key256,
+        sealing: Option<SealingKeys>,
+    ) -> Result<InodeDb> {
+        let db = InodeDb {
             tree,
-            data_enc_key,
-            data_hash_key,
-            entry_type: PhantomData,
+            inode_enc_key: Locked::new(inode_enc_key),
+            inode_hash_key: Locked::new(inode_hash_key),
+            sealing,
         };
         db.self_test()?;
         Ok(db)
     }
 
-    /// Returns the number of stored objects in the database
-    ///
-    /// **This performs an O(n) scan**
-    pub fn len(&self) -> usize {
-        self.tree.len()
+    fn encrypt_entry(
+        &self,
+        key: &Hash256,
+        ref_count: RefCount,
+        data: &InodeData,
+    ) -> Result<Vec<u8>> {
+        let aad = associated_data(ObjectType::InodeDbEntry, key);
+        match data {
+            InodeData::Plain(inode) => InodeDbEntry {
+                inode: inode.clone(),
+                ref_count,
+            }
+            .encrypt_with_aad(&self.inode_enc_key, &aad),
+            InodeData::Sealed(inode) => SealedInodeDbEntry {
+                inode: inode.clone(),
+                ref_count,
+            }
+            .encrypt_with_aad(&self.inode_enc_key, &aad),
+        }
     }
 
-    /// Inserts data into the database
-    /// If the same data is already stored the reference count is incremented
-    pub fn insert(&mut self, data: T) -> Result<(RefCount, Hash256)> {
-        let key = Hash256::from(*data.keyed_hash(&self.data_hash_key)?.as_bytes());
-        match self.tree.remove(key)? {
-            Some(old) => {
-                let old = RcDbEntry::<T>::decrypt(&old, &self.data_enc_key)?;
-                let ref_count = old.ref_count + 1;
-
-                self.tree.insert(
-                    key,
-                    RcDbEntry { data, ref_count }.encrypt(&self.data_enc_key)?,
-                )?;
-
-                Ok((ref_count, key))
-            }
+    fn decrypt_entry(&self, key: &Hash256, encrypted_data: &[u8]) -> Result<(RefCount, InodeData)> {
+        let aad = associated_data(ObjectType::InodeDbEntry, key);
+        match self.sealing {
             None => {
-                let encrypted_entry =
-                    RcDbEntry { data, ref_count: 1 }.encrypt(&self.data_enc_key)?;
-                self.tree.insert(key, encrypted_entry)?;
-                Ok((1, key))
+                let entry =
+                    InodeDbEntry::decrypt_with_aad(encrypted_data, &self.inode_enc_key, &aad)?;
+                Ok((entry.ref_count, InodeData::Plain(entry.inode)))
+            }
+            Some(_) => {
+                let entry = SealedInodeDbEntry::decrypt_with_aad(
+                    encrypted_data,
+                    &self.inode_enc_key,
+                    &aad,
+                )?;
+                Ok((entry.ref_count, InodeData::Sealed(entry.inode)))
             }
         }
     }
 
-    /// Removes an instace of the referenced data from the database.
-    /// If the reference count reaches 0 the element will be deleted.
-    pub fn remove(&mut self, key: &Hash256) -> Result<Option<(RefCount, T)>> {
-        match self.tree.remove(key)? {
-            None => Ok(None),
-            Some(old) => {
-                let old = RcDbEntry::decrypt(&old, &self.data_enc_key)?;
-                if old.ref_count <= 1 {
-                    Ok(Some((0, old.data)))
-                } else {
-                    let ref_count = old.ref_count - 1;
+    fn seal_inode(&self, key: &Hash256, inode: Inode) -> Result<InodeData> {
+        match &self.sealing {
+            None => Ok(InodeData::Plain(inode)),
+            Some(sealing) => Ok(InodeData::Sealed(inode.seal_with_aad(
+                &sealing.public_key,
+                &associated_data(ObjectType::SealedInode, key),
+            )?)),
+        }
+    }
 
-                    let encrypted_entry = RcDbEntry {
-                        data: old.data.clone(),
-                        ref_count,
-                    }
-                    .encrypt(&self.data_enc_key)?;
-                    self.tree.insert(key, encrypted_entry)?;
-                    Ok(Some((ref_count, old.data)))
-                }
+    /// Returns the inode, [`BackrubError::WriteOnlyAccess`] if it is sealed and can not be opened
+    fn open_inode(&self, key: &Hash256, data: &InodeData) -> Result<Inode> {
+        match (data, &self.sealing) {
+            (InodeData::Plain(inode), _) => Ok(inode.clone()),
+            (InodeData::Sealed(_), None) => Err(BackrubError::WriteOnlyAccess.into()),
+            (InodeData::Sealed(inode), Some(sealing)) => Inode::open_with_aad(
+                inode,
+                sealing.secret_key()?,
+                &associated_data(ObjectType::SealedInode, key),
+            ),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Inserts an inode or increments its reference count if it exists
+    ///
+    /// This works on write-only clients, as the inode of an existing entry is not needed.
+    pub fn insert(&mut self, inode: Inode) -> Result<(RefCount, Hash256)> {
+        let key = Hash256::from(*inode.keyed_hash(&self.inode_hash_key)?.as_bytes());
+        update_entry(&self.tree, &key, |old| {
+            let (ref_count, data) = match old {
+                // the inode is identical, as the key is its hash
+                Some(old) => self.decrypt_entry(&key, old)?,
+                None => (0, self.seal_inode(&key, inode.clone())?),
+            };
+            let ref_count = ref_count + 1;
+            Ok((
+                Some(self.encrypt_entry(&key, ref_count, &data)?),
+                (ref_count, key),
+            ))
+        })
+    }
+
+    pub fn remove(&mut self, key: &Hash256) -> Result<Option<(RefCount, Inode)>> {
+        update_entry(&self.tree, key, |old| {
+            let old = match old {
+                None => return Ok((None, None)),
+                Some(old) => old,
+            };
+            let (ref_count, data) = self.decrypt_entry(key, old)?;
+            // open the inode before the entry is changed, this fails on write-only clients
+            let inode = self.open_inode(key, &data)?;
+
+            if ref_count <= 1 {
+                Ok((None, Some((0, inode))))
+            } else {
+                let ref_count = ref_count - 1;
+                Ok((
+                    Some(self.encrypt_entry(key, ref_count, &data)?),
+                    Some((ref_count, inode)),
+                ))
             }
+        })
+    }
+
+    /// Adds a reference to an inode in `batch` and returns its key, like [`Self::insert`] does
+    pub fn insert_batched(&self, batch: &mut RefCountBatch, inode: Inode) -> Result<Hash256> {
+        let key = Hash256::from(*inode.keyed_hash(&self.inode_hash_key)?.as_bytes());
+        let refs = batch.inodes.entry(key).or_default();
+        // only inodes that are not stored yet are kept until the batch is committed
+        if refs.new.is_none() && !self.tree.contains_key(key)? {
+            refs.new = Some(inode);
         }
+        refs.added += 1;
+        Ok(key)
     }
 
-    /// Deletes the referenced entry from the database regardless of the reference count
-    pub fn purge(&mut self, key: &Hash256) -> Result<Option<(RefCount, T)>> {
-        match self.tree.remove(key)? {
-            None => Ok(None),
-            Some(old) => {
-                let old = RcDbEntry::decrypt(&old, &self.data_enc_key)?;
-                Ok(Some((old.ref_count, old.data)))
+    /// Removes an inode reference in `batch`, like [`Self::remove`] does
+    ///
+    /// The returned reference count includes the changes of `batch`.
+    pub fn remove_batched(
+        &self,
+        batch: &mut RefCountBatch,
+        key: &Hash256,
+    ) -> Result<Option<(RefCount, Inode)>> {
+        let stored = self.get_inode_db_entry(key)?;
+        let refs = batch.inodes.entry(*key).or_default();
+        let (ref_count, inode) = match (stored, &refs.new) {
+            (Some(entry), _) => entry,
+            (None, Some(inode)) => (0, inode.clone()),
+            (None, None) => return Ok(None),
+        };
+        match refs.apply(ref_count) {
+            0 => Ok(None),
+            ref_count => {
+                refs.removed += 1;
+                Ok(Some((ref_count - 1, inode)))
             }
         }
     }
 
-    /// Gets the current refercence count and data
-    pub fn get_data_db_entry(&self, key: &Hash256) -> Result<Option<(RefCount, T)>> {
+    pub fn get_inode_db_entry(&self, key: &Hash256) -> Result<Option<(RefCount, Inode)>> {
         match self.tree.get(key)? {
             None => Ok(None),
             Some(encrypted_data) => {
-                let entry = RcDbEntry::decrypt(&encrypted_data, &self.data_enc_key)?;
-                Ok(Some((entry.ref_count, entry.data)))
+                let (ref_count, data) = self.decrypt_entry(key, &encrypted_data)?;
+                Ok(Some((ref_count, self.open_inode(key, &data)?)))
             }
         }
     }
 
-    /// Gets only the referenced data
-    pub fn get_data(&self, key: &Hash256) -> Result<Option<T>> {
-        match self.get_data_db_entry(key)? {
+    pub fn get_inode(&self, key: &Hash256) -> Result<Option<Inode>> {
+        match self.get_inode_db_entry(key)? {
             None => Ok(None),
-            Some((_ref_count, data)) => Ok(Some(data)),
+            Some((_ref_count, inode)) => Ok(Some(inode)),
         }
     }
 
-    /// Gets only the reference count
     pub fn get_ref_count(&self, key: &Hash256) -> Result<Option<RefCount>> {
-        match self.get_data_db_entry(key)? {
+        match self.tree.get(key)? {
             None => Ok(None),
-            Some((ref_count, _data)) => Ok(Some(ref_count)),
+            Some(encrypted_data) => Ok(Some(self.decrypt_entry(key, &encrypted_data)?.0)),
         }
     }
 
-    /// Returns a complete in memory representation of the database
-    pub fn get_mappings(&self) -> Result<BTreeMap<Hash256, (RefCount, T)>> {
-        let mut result = BTreeMap::<Hash256, (RefCount, T)>::new();
+    pub fn get_mappings(&self) -> Result<BTreeMap<Hash256, (RefCount, Inode)>> {
+        let mut result = BTreeMap::<Hash256, (RefCount, Inode)>::new();
         for data in self.tree.iter() {
             let (key, encrypted_data) = data?;
             if key.len() != HASH_SIZE {
@@ -394,35 +735,31 @@ impl<T: Clone + Hashable + Serialize + for<'a> Deserialize<'a>> RcDb<T> {
                         Ok,
                     )?
                     .try_into()?;
-                let entry = RcDbEntry::decrypt(&encrypted_data, &self.data_enc_key)?;
-                result.insert(hash, (entry.ref_count, entry.data));
+                let (ref_count, data) = self.decrypt_entry(&hash, &encrypted_data)?;
+                result.insert(hash, (ref_count, self.open_inode(&hash, &data)?));
             }
         }
         Ok(result)
     }
 }
 
-#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
-struct InodeDbEntry {
-    inode: Inode,
-    ref_count: RefCount,
-}
-
-impl Encrypt for InodeDbEntry {}
-
+/// BackupDb stores the encrypted [`Backup`] records of a repository keyed by their id
 #[derive(Debug)]
-pub struct InodeDb {
+pub struct BackupDb {
     tree: sled::Tree,
-    inode_enc_key: Key256,
-    inode_hash_key: Key256,
+    backup_enc_key: Locked<Key256>,
+    sealing: Option<SealingKeys>,
 }
 
-impl InodeDb {
+impl BackupDb {
+    /// Check the BackupDb contents for errors
+    ///
+    /// This is a O(n) operation
     pub fn self_test(&self) -> Result<()> {
         for data in self.tree.iter() {
             let (key, encrypted_data) = data?;
 
-            // Check Key
+            // Check key
             if key.len() != HASH_SIZE {
                 return Err(BackrubError::SledKeyLengthError.into());
             }
@@ -435,120 +772,369 @@ impl InodeDb {
                 )?
                 .try_into()?;
 
-            // Check data
-            let entry = InodeDbEntry::decrypt(&encrypted_data, &self.inode_enc_key)?;
-            if key != Hash256::from(*entry.inode.keyed_hash(&self.inode_hash_key)?.as_bytes()) {
+            // Check data, sealed records can not be checked by write-only clients
+            if self
+                .sealing
+                .as_ref()
+                .is_some_and(|sealing| !sealing.can_open())
+            {
+                continue;
+            }
+            let backup = self.decrypt_backup(&key, &encrypted_data)?;
+            if key != backup.id {
                 return Err(BackrubError::SelfTestError.into());
             }
         }
         Ok(())
     }
 
-    pub fn new(tree: sled::Tree, inode_enc_key: Key256, inode_hash_key: Key256) -> Result<InodeDb> {
-        let db = InodeDb {
+    /// Creates a BackupDb from a `sled::Tree` and runs a self test
+    pub fn new(tree: sled::Tree, backup_enc_key: Key256) -> Result<BackupDb> {
+        Self::with_sealing(tree, backup_enc_key, None)
+    }
+
+    /// Creates a BackupDb whose records are sealed with `sealing` if it is given
+    pub fn with_sealing(
+        tree: sled::Tree,
+        backup_enc_key: Key256,
+        sealing: Option<SealingKeys>,
+    ) -> Result<BackupDb> {
+        let db = BackupDb {
             tree,
-            inode_enc_key,
-            inode_hash_key,
+            backup_enc_key: Locked::new(backup_enc_key),
+            sealing,
         };
         db.self_test()?;
         Ok(db)
     }
 
+    fn encrypt_backup(&self, backup: &Backup) -> Result<Vec<u8>> {
+        let aad = associated_data(ObjectType::Backup, &backup.id);
+        match &self.sealing {
+            None => backup.encrypt_with_aad(&self.backup_enc_key, &aad),
+            Some(sealing) => backup.seal_with_aad(&sealing.public_key, &aad),
+        }
+    }
+
+    fn decrypt_backup(&self, id: &Hash256, encrypted_data: &[u8]) -> Result<Backup> {
+        let aad = associated_data(ObjectType::Backup, id);
+        match &self.sealing {
+            None => Backup::decrypt_with_aad(encrypted_data, &self.backup_enc_key, &aad),
+            Some(sealing) => Backup::open_with_aad(encrypted_data, sealing.secret_key()?, &aad),
+        }
+    }
+
+    /// Returns the number of stored backups
+    ///
+    /// This performs a full O(n) scan
     pub fn len(&self) -> usize {
         self.tree.len()
     }
 
-    pub fn insert(&mut self, inode: Inode) -> Result<(RefCount, Hash256)> {
-        let key = Hash256::from(*inode.keyed_hash(&self.inode_hash_key)?.as_bytes());
-        match self.tree.remove(key)? {
-            Some(old) => {
-                let old = InodeDbEntry::decrypt(&old, &self.inode_enc_key)?;
-                let ref_count = old.ref_count + 1;
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
 
-                self.tree.insert(
-                    key,
-                    InodeDbEntry { inode, ref_count }.encrypt(&self.inode_enc_key)?,
-                )?;
+    /// Inserts a backup record, an existing record with the same id is replaced
+    pub fn insert(&mut self, backup: &Backup) -> Result<()> {
+        self.tree.insert(backup.id, self.encrypt_backup(backup)?)?;
+        Ok(())
+    }
 
-                Ok((ref_count, key))
-            }
-            None => {
-                let encrypted_entry = InodeDbEntry {
-                    inode,
-                    ref_count: 1,
-                }
-                .encrypt(&self.inode_enc_key)?;
-                self.tree.insert(key, encrypted_entry)?;
-                Ok((1, key))
-            }
+    /// Removes a backup record and returns it
+    pub fn remove(&mut self, id: &Hash256) -> Result<Option<Backup>> {
+        match self.tree.remove(id)? {
+            None => Ok(None),
+            Some(encrypted_data) => Ok(Some(self.decrypt_backup(id, &encrypted_data)?)),
         }
     }
 
-    pub fn remove(&mut self, key: &Hash256) -> Result<Option<(RefCount, Inode)>> {
-        match self.tree.remove(key)? {
+    pub fn get(&self, id: &Hash256) -> Result<Option<Backup>> {
+        match self.tree.get(id)? {
             None => Ok(None),
-            Some(old) => {
-                let old = InodeDbEntry::decrypt(&old, &self.inode_enc_key)?;
-                if old.ref_count <= 1 {
-                    Ok(Some((0, old.inode)))
-                } else {
-                    let ref_count = old.ref_count - 1;
-
-                    let encrypted_entry = InodeDbEntry {
-                        inode: old.inode.clone(),
-                        ref_count,
-                    }
-                    .encrypt(&self.inode_enc_key)?;
-                    self.tree.insert(key, encrypted_entry)?;
-                    Ok(Some((ref_count, old.inode)))
-                }
-            }
+            Some(encrypted_data) => Ok(Some(self.decrypt_backup(id, &encrypted_data)?)),
         }
     }
 
-    pub fn get_inode_db_entry(&self, key: &Hash256) -> Result<Option<(RefCount, Inode)>> {
-        match self.tree.get(key)? {
-            None => Ok(None),
-            Some(encrypted_data) => {
-                let entry = InodeDbEntry::decrypt(&encrypted_data, &self.inode_enc_key)?;
-                Ok(Some((entry.ref_count, entry.inode)))
-            }
+    /// Returns all backups sorted by their timestamp
+    ///
+    /// This decrypts all records
+    pub fn get_all(&self) -> Result<Vec<Backup>> {
+        let mut result = Vec::<Backup>::with_capacity(self.tree.len());
+        for data in self.tree.iter() {
+            let (key, encrypted_data) = data?;
+            let id = Hash256::try_from(key.as_ref())?;
+            result.push(self.decrypt_backup(&id, &encrypted_data)?);
         }
+        result.sort_by(|l, r| l.timestamp.cmp(&r.timestamp));
+        Ok(result)
     }
+}
 
-    pub fn get_inode(&self, key: &Hash256) -> Result<Option<Inode>> {
-        match self.get_inode_db_entry(key)? {
-            None => Ok(None),
-            Some((_ref_count, inode)) => Ok(Some(inode)),
+/// Pending changes of the references to an inode or chunk
+#[derive(Clone, Debug)]
+struct PendingRefs<T> {
+    added: RefCount,
+    removed: RefCount,
+    /// Data of an entry that is not stored yet
+    new: Option<T>,
+}
+
+impl<T> Default for PendingRefs<T> {
+    fn default() -> Self {
+        PendingRefs {
+            added: 0,
+            removed: 0,
+            new: None,
         }
     }
+}
 
-    pub fn get_ref_count(&self, key: &Hash256) -> Result<Option<RefCount>> {
-        match self.get_inode_db_entry(key)? {
-            None => Ok(None),
-            Some((ref_count, _inode)) => Ok(Some(ref_count)),
+impl<T> PendingRefs<T> {
+    /// Reference count after the changes of an entry that has `ref_count` references now
+    fn apply(&self, ref_count: RefCount) -> RefCount {
+        (ref_count + self.added).saturating_sub(self.removed)
+    }
+}
+
+/// Reference count changes that are applied together with the backup records they belong to
+///
+/// The changes of a backup run or of deleting a backup are collected with the `*_batched`
+/// methods of [`InodeDb`] and [`ChunkDb`] and written in one transaction by [`Self::commit`],
+/// so an interrupted run does not leave references that no backup record accounts for.
+/// Relocated chunks only point to their new location once their data is stored.
+#[derive(Debug, Default)]
+pub struct RefCountBatch {
+    inodes: BTreeMap<Hash256, PendingRefs<Inode>>,
+    chunks: BTreeMap<Hash256, PendingRefs<ChunkLocation>>,
+    /// New locations of stored chunks
+    relocations: BTreeMap<Hash256, ChunkLocation>,
+    /// Backup records to write, `None` removes the record
+    backups: BTreeMap<Hash256, Option<Backup>>,
+}
+
+impl RefCountBatch {
+    /// Inserts a backup record when the batch is committed
+    pub fn insert_backup(&mut self, backup: Backup) {
+        self.backups.insert(backup.id, Some(backup));
+    }
+
+    /// Removes a backup record when the batch is committed
+    pub fn remove_backup(&mut self, id: &Hash256) {
+        self.backups.insert(*id, None);
+    }
+
+    /// Applies all changes atomically and returns the locations of the chunks that are no
+    /// longer referenced
+    ///
+    /// Unpacked chunk files of the returned chunks have to be removed by the caller, their names
+    /// are reused. Nothing is changed if an error is returned.
+    pub fn commit(
+        self,
+        inode_db: &InodeDb,
+        chunk_db: &mut ChunkDb,
+        backup_db: &BackupDb,
+    ) -> Result<Vec<ChunkLocation>> {
+        let chunk_db_ref = &*chunk_db;
+        let released = (&inode_db.tree, &chunk_db_ref.chunk_map, &backup_db.tree)
+            .transaction(|(inodes, chunks, backups)| {
+                for (key, refs) in self.inodes.iter() {
+                    let (ref_count, data) = match (inodes.get(key)?, &refs.new) {
+                        (Some(old), _) => abort(inode_db.decrypt_entry(key, &old))?,
+                        (None, Some(inode)) => (0, abort(inode_db.seal_inode(key, inode.clone()))?),
+                        (None, None) if refs.added == 0 => continue,
+                        (None, None) => {
+                            return abort(Err(BackrubError::InodeDidNotExist(*key).into()))
+                        }
+                    };
+                    match refs.apply(ref_count) {
+                        0 => {
+                            inodes.remove(key.as_ref())?;
+                        }
+                        ref_count => {
+                            let entry = abort(inode_db.encrypt_entry(key, ref_count, &data))?;
+                            inodes.insert(key.as_ref(), entry)?;
+                        }
+                    }
+                }
+
+                let mut released = Vec::<ChunkLocation>::new();
+                for (key, refs) in self.chunks.iter() {
+                    let mut entry = match (chunks.get(key)?, &refs.new) {
+                        (Some(old), _) => abort(chunk_db_ref.decrypt_entry(key, &old))?,
+                        (None, Some(location)) => ChunkDbEntry {
+                            ref_count: 0,
+                            file_name: location.file_name.clone(),
+                            range: location.range,
+                        },
+                        (None, None) if refs.added == 0 => continue,
+                        (None, None) => {
+                            return abort(Err(BackrubError::ChunkDidNotExist(*key).into()))
+                        }
+                    };
+                    entry.ref_count = refs.apply(entry.ref_count);
+                    if entry.ref_count == 0 {
+                        chunks.remove(key.as_ref())?;
+                        released.push(entry.location());
+                    } else {
+                        let entry = abort(chunk_db_ref.encrypt_entry(key, &entry))?;
+                        chunks.insert(key.as_ref(), entry)?;
+                    }
+                }
+
+                for (key, location) in self.relocations.iter() {
+                    let mut entry = match chunks.get(key)? {
+                        Some(old) => abort(chunk_db_ref.decrypt_entry(key, &old))?,
+                        // the last reference was removed by this batch
+                        None if self.chunks.contains_key(key) => continue,
+                        None => return abort(Err(BackrubError::ChunkDidNotExist(*key).into())),
+                    };
+                    entry.file_name = location.file_name.clone();
+                    entry.range = location.range;
+                    let entry = abort(chunk_db_ref.encrypt_entry(key, &entry))?;
+                    chunks.insert(key.as_ref(), entry)?;
+                }
+
+                for (id, backup) in self.backups.iter() {
+                    match backup {
+                        Some(backup) => {
+                            backups
+                                .insert(id.as_ref(), abort(backup_db.encrypt_backup(backup))?)?;
+                        }
+                        None => {
+                            backups.remove(id.as_ref())?;
+                        }
+                    }
+                }
+
+                Ok(released)
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => Error::from(e),
+            })?;
+
+        // save old file names for reuse, packs are still used by other chunks
+        for location in released.iter() {
+            if !location.is_packed() {
+                chunk_db.release_file_name(location.file_name.clone());
+            }
         }
+        Ok(released)
     }
+}
 
-    pub fn get_mappings(&self) -> Result<BTreeMap<Hash256, (RefCount, Inode)>> {
-        let mut result = BTreeMap::<Hash256, (RefCount, Inode)>::new();
+/// Entry of the [`FilesCache`]
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FilesCacheEntry {
+    pub metadata: Metadata,
+    pub chunk_ids: Vec<Hash256>,
+    pub file_hash: Hash256,
+}
+
+impl Encrypt for FilesCacheEntry {}
+
+impl FilesCacheEntry {
+    /// Returns `true` if the file described by `metadata` is unchanged
+    ///
+    /// Device, inode number, size, mtime and ctime have to match
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+        self.metadata.dev == metadata.dev
+            && self.metadata.ino == metadata.ino
+            && self.metadata.size == metadata.size
+            && self.metadata.mtime == metadata.mtime
+            && self.metadata.mtime_ns == metadata.mtime_ns
+            && self.metadata.ctime == metadata.ctime
+            && self.metadata.ctime_ns == metadata.ctime_ns
+    }
+}
+
+/// FilesCache remembers the chunks of already backed up files by their absolute path
+///
+/// Paths are only stored as keyed hashes, the entries are encrypted
+#[derive(Debug)]
+pub struct FilesCache {
+    tree: sled::Tree,
+    enc_key: Locked<Key256>,
+    hash_key: Locked<Key256>,
+}
+
+impl FilesCache {
+    /// Check the FilesCache contents for errors
+    ///
+    /// This is a O(n) operation
+    pub fn self_test(&self) -> Result<()> {
         for data in self.tree.iter() {
             let (key, encrypted_data) = data?;
+            // Check key
             if key.len() != HASH_SIZE {
                 return Err(BackrubError::SledKeyLengthError.into());
-            } else {
-                let hash: Hash256 = key
-                    .chunks_exact(HASH_SIZE)
-                    .next()
-                    .map_or_else(
-                        || Err::<&[u8], BackrubError>(BackrubError::SledKeyLengthError),
-                        Ok,
-                    )?
-                    .try_into()?;
-                let entry = InodeDbEntry::decrypt(&encrypted_data, &self.inode_enc_key)?;
-                result.insert(hash, (entry.ref_count, entry.inode));
             }
+            let key = Hash256::try_from(key.as_ref())?;
+            // Check data
+            let _ = self.decrypt_entry(&key, &encrypted_data)?;
+        }
+        Ok(())
+    }
+
+    /// Creates a FilesCache from a `sled::Tree` and runs a self test
+    pub fn new(tree: sled::Tree, enc_key: Key256, hash_key: Key256) -> Result<FilesCache> {
+        let cache = FilesCache {
+            tree,
+            enc_key: Locked::new(enc_key),
+            hash_key: Locked::new(hash_key),
+        };
+        cache.self_test()?;
+        Ok(cache)
+    }
+
+    fn key(&self, path: &Path) -> Result<Hash256> {
+        Ok(Hash256::from(
+            path.as_os_str()
+                .as_bytes()
+                .keyed_hash(&self.hash_key)?
+                .as_bytes(),
+        ))
+    }
+
+    fn encrypt_entry(&self, key: &Hash256, entry: &FilesCacheEntry) -> Result<Vec<u8>> {
+        entry.encrypt_with_aad(
+            &self.enc_key,
+            &associated_data(ObjectType::FilesCacheEntry, key),
+        )
+    }
+
+    fn decrypt_entry(&self, key: &Hash256, encrypted_data: &[u8]) -> Result<FilesCacheEntry> {
+        FilesCacheEntry::decrypt_with_aad(
+            encrypted_data,
+            &self.enc_key,
+            &associated_data(ObjectType::FilesCacheEntry, key),
+        )
+    }
+
+    /// Returns the number of cached files
+    ///
+    /// This performs a full O(n) scan
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Inserts or replaces the entry for `path`
+    pub fn insert(&mut self, path: &Path, entry: &FilesCacheEntry) -> Result<()> {
+        let key = self.key(path)?;
+        self.tree.insert(key, self.encrypt_entry(&key, entry)?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, path: &Path) -> Result<Option<FilesCacheEntry>> {
+        let key = self.key(path)?;
+        match self.tree.get(key)? {
+            None => Ok(None),
+            Some(encrypted_data) => Ok(Some(self.decrypt_entry(&key, &encrypted_data)?)),
         }
-        Ok(result)
     }
 }
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use std::{error, fmt, path::PathBuf};
 
+use crate::structs::Hash256;
+
 /// Error type for errors that are specific for backrub.
 ///
 /// For all practical purposes this will be wrapped into [Error].
@@ -12,7 +14,37 @@ pub enum BackrubError {
     SledDbDidNotExist(PathBuf),
     SelfTestError,
     InvalidSignature,
+    WrongPassword,
+    KeySlotExists(String),
+    KeySlotDidNotExist(String),
+    LastKeySlot,
+    UnsupportedManifestVersion(u64),
+    ManifestRollback(u64, u64),
+    ManifestForked(u64),
+    WriteOnlyAccess,
+    KeyPairMismatch,
+    NoKeyPair,
+    InvalidRecoveryKey(String),
+    RecoveryKeyTypo(usize),
+    ManifestAlreadyExists(PathBuf),
+    EmptyPassword,
+    EnvVarNotSet(String),
+    PasswordCommandFailed(Option<i32>),
+    InvalidKeyFile(PathBuf),
+    InvalidPasswordSource(String),
+    InvalidArgon2Conf(String),
+    InvalidCompression(String),
     BackupRootMustBeDir(PathBuf),
+    BackupDidNotExist(Hash256),
+    BackupNotFinished(Hash256),
+    InodeDidNotExist(Hash256),
+    ChunkDidNotExist(Hash256),
+    InvalidPackFile(PathBuf),
+    StorageError(String),
+    RepositoryLocked(String),
+    PathNotInBackup(PathBuf),
+    RestoreTargetExists(PathBuf),
+    EmptyRetentionPolicy,
 }
 
 impl fmt::Display for BackrubError {
@@ -24,6 +56,121 @@ impl fmt::Display for BackrubError {
                     "InvalidSignature: a signature is invalid, this could be a sign of tampering"
                 )
             }
+            BackrubError::WrongPassword => {
+                write!(
+                    f,
+                    "WrongPassword: the password does not unlock any key slot"
+                )
+            }
+            BackrubError::KeySlotExists(label) => {
+                write!(
+                    f,
+                    "KeySlotExists: a key slot labeled {:?} already exists",
+                    label
+                )
+            }
+            BackrubError::KeySlotDidNotExist(label) => {
+                write!(f, "KeySlotDidNotExist: no key slot is labeled {:?}", label)
+            }
+            BackrubError::LastKeySlot => {
+                write!(f, "LastKeySlot: the last key slot can not be removed")
+            }
+            BackrubError::UnsupportedManifestVersion(version) => {
+                write!(
+                    f,
+                    "UnsupportedManifestVersion: manifest format version {} is not supported",
+                    version
+                )
+            }
+            BackrubError::ManifestRollback(seen, found) => {
+                write!(
+                    f,
+                    "ManifestRollback: manifest generation {} is older than the already seen generation {}",
+                    found, seen
+                )
+            }
+            BackrubError::ManifestForked(generation) => {
+                write!(
+                    f,
+                    "ManifestForked: manifest generation {} differs from the one seen before",
+                    generation
+                )
+            }
+            BackrubError::WriteOnlyAccess => {
+                write!(
+                    f,
+                    "WriteOnlyAccess: the repository was opened with write-only keys, this needs a key slot"
+                )
+            }
+            BackrubError::KeyPairMismatch => {
+                write!(
+                    f,
+                    "KeyPairMismatch: the public key does not belong to the repository's key pair"
+                )
+            }
+            BackrubError::NoKeyPair => {
+                write!(
+                    f,
+                    "NoKeyPair: the repository was not created in write-only mode"
+                )
+            }
+            BackrubError::InvalidRecoveryKey(reason) => {
+                write!(f, "InvalidRecoveryKey: {}", reason)
+            }
+            BackrubError::RecoveryKeyTypo(line) => {
+                write!(
+                    f,
+                    "RecoveryKeyTypo: line {} of the recovery key does not match its checksum",
+                    line
+                )
+            }
+            BackrubError::ManifestAlreadyExists(path) => {
+                write!(
+                    f,
+                    "ManifestAlreadyExists: refusing to overwrite the manifest \"{}\"",
+                    path.display()
+                )
+            }
+            BackrubError::EmptyPassword => {
+                write!(
+                    f,
+                    "EmptyPassword: the password source yielded an empty password"
+                )
+            }
+            BackrubError::EnvVarNotSet(name) => {
+                write!(
+                    f,
+                    "EnvVarNotSet: environment variable {:?} is not set",
+                    name
+                )
+            }
+            BackrubError::PasswordCommandFailed(code) => match code {
+                Some(code) => write!(
+                    f,
+                    "PasswordCommandFailed: password command exited with code {}",
+                    code
+                ),
+                None => write!(
+                    f,
+                    "PasswordCommandFailed: password command was terminated by a signal"
+                ),
+            },
+            BackrubError::InvalidKeyFile(path) => {
+                write!(f, "InvalidKeyFile: {:?} does not contain a valid key", path)
+            }
+            BackrubError::InvalidPasswordSource(source) => {
+                write!(
+                    f,
+                    "InvalidPasswordSource: {:?} is not a valid password source",
+                    source
+                )
+            }
+            BackrubError::InvalidArgon2Conf(reason) => {
+                write!(f, "InvalidArgon2Conf: {}", reason)
+            }
+            BackrubError::InvalidCompression(reason) => {
+                write!(f, "InvalidCompression: {}", reason)
+            }
             BackrubError::BackupRootMustBeDir(path) => {
                 write!(
                     f,
@@ -31,6 +178,67 @@ impl fmt::Display for BackrubError {
                     path.display()
                 )
             }
+            BackrubError::BackupDidNotExist(key) => {
+                write!(
+                    f,
+                    "BackupDidNotExist: no backup is stored under key {:?}",
+                    key
+                )
+            }
+            BackrubError::BackupNotFinished(key) => {
+                write!(
+                    f,
+                    "BackupNotFinished: the backup {:?} was interrupted and can not be used",
+                    key
+                )
+            }
+            BackrubError::InodeDidNotExist(key) => {
+                write!(
+                    f,
+                    "InodeDidNotExist: no inode is stored under key {:?}",
+                    key
+                )
+            }
+            BackrubError::ChunkDidNotExist(key) => {
+                write!(
+                    f,
+                    "ChunkDidNotExist: no chunk is stored under key {:?}",
+                    key
+                )
+            }
+            BackrubError::InvalidPackFile(path) => {
+                write!(
+                    f,
+                    "InvalidPackFile: \"{}\" does not end with a pack index",
+                    path.display()
+                )
+            }
+            BackrubError::StorageError(reason) => {
+                write!(f, "StorageError: {}", reason)
+            }
+            BackrubError::RepositoryLocked(holder) => {
+                write!(f, "RepositoryLocked: the repository is locked, {}", holder)
+            }
+            BackrubError::PathNotInBackup(path) => {
+                write!(
+                    f,
+                    "PathNotInBackup: the path \"{}\" is not part of the backup",
+                    path.display()
+                )
+            }
+            BackrubError::RestoreTargetExists(path) => {
+                write!(
+                    f,
+                    "RestoreTargetExists: refusing to overwrite existing file \"{}\"",
+                    path.display()
+                )
+            }
+            BackrubError::EmptyRetentionPolicy => {
+                write!(
+                    f,
+                    "EmptyRetentionPolicy: refusing to apply a retention policy that keeps nothing"
+                )
+            }
             BackrubError::SledDbAlreadyExists(path) => {
                 write!(
                     f,
@@ -121,8 +329,20 @@ impl_error_enum!(
         TryFromSliceError(std::array::TryFromSliceError),
         SerdeJsonError(serde_json::Error),
         Argon2Error(argon2::Error),
+        ChronoParseError(chrono::ParseError),
     }
 );
 
+impl Error {
+    /// Kind of a wrapped [`std::io::Error`], storage backends report missing objects as
+    /// [`std::io::ErrorKind::NotFound`]
+    pub fn io_error_kind(&self) -> Option<std::io::ErrorKind> {
+        match self {
+            Error::IoError(e) => Some(e.kind()),
+            _ => None,
+        }
+    }
+}
+
 /// Backrub specific result wrapper, using [Error].
 pub type Result<T> = std::result::Result<T, Error>;
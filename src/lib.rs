@@ -20,6 +20,27 @@ pub mod manager;
 /// Databases
 pub mod db;
 
+/// Pack files that hold many encrypted chunks
+pub mod pack;
+
+/// Storage backends for the chunk data
+pub mod storage;
+
+/// Retention policies for pruning backups
+pub mod retention;
+
+/// Password sources for unlocking repositories
+pub mod password;
+
+/// Locks that keep concurrent clients from changing a repository at the same time
+pub mod lock;
+
+/// Client-local state that protects against rolled back repositories
+pub mod state;
+
+/// Recovery keys for restoring a lost manifest
+pub mod recovery;
+
 /// Utility functions
 pub mod utils;
 
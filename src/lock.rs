@@ -0,0 +1,258 @@
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use super::error::*;
+use super::utils::*;
+
+/// Locks that were not refreshed for this long are stale, their process is gone
+pub const STALE_LOCK_AGE: Duration = Duration::from_secs(30 * 60);
+/// How often a held lock is refreshed
+const LOCK_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Copy, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LockKind {
+    /// Held by operations that only read the repository, any number of them can run at once
+    Shared,
+    /// Held by operations that change the repository, nothing else can hold a lock meanwhile
+    Exclusive,
+}
+
+/// Contents of a lock file, who holds the lock and since when
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LockInfo {
+    pub kind: LockKind,
+    pub hostname: String,
+    pub pid: u32,
+    /// RFC 3339 time at which the lock was taken or last refreshed
+    pub timestamp: String,
+}
+
+impl fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            LockKind::Shared => "shared",
+            LockKind::Exclusive => "exclusive",
+        };
+        write!(
+            f,
+            "{} lock of process {} on {:?}, refreshed at {}",
+            kind, self.pid, self.hostname, self.timestamp
+        )
+    }
+}
+
+impl LockInfo {
+    /// Lock of the current process
+    fn new(kind: LockKind) -> Self {
+        LockInfo {
+            kind,
+            hostname: whoami::fallible::hostname().unwrap_or_default(),
+            pid: std::process::id(),
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        }
+    }
+
+    /// Whether the lock was left behind by a process that is gone
+    ///
+    /// That is the case if it was not refreshed for [`STALE_LOCK_AGE`], or if it was taken on
+    /// this host by a process that does not exist anymore.
+    pub fn is_stale(&self) -> bool {
+        let expired = DateTime::parse_from_rfc3339(&self.timestamp).is_ok_and(|timestamp| {
+            Utc::now()
+                .signed_duration_since(timestamp)
+                .to_std()
+                .is_ok_and(|age| age > STALE_LOCK_AGE)
+        });
+        let this_host = whoami::fallible::hostname().is_ok_and(|host| host == self.hostname);
+
+        expired || (this_host && !process_exists(self.pid))
+    }
+
+    fn conflicts_with(&self, kind: LockKind) -> bool {
+        self.kind == LockKind::Exclusive || kind == LockKind::Exclusive
+    }
+}
+
+/// Checks with the null signal whether a process of this host exists
+fn process_exists(pid: u32) -> bool {
+    // pids that do not fit would address process groups
+    match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => match unsafe { libc::kill(pid, 0) } {
+            0 => true,
+            // the process exists but belongs to someone else
+            _ => std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM),
+        },
+        _ => false,
+    }
+}
+
+/// Lock files of a repository, one per lock in a directory next to the manifest
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RepositoryLocks {
+    dir: PathBuf,
+}
+
+impl RepositoryLocks {
+    /// Locks of the repository with the manifest `manifest_path`, `backrub.manifest.locks` for
+    /// `backrub.manifest`
+    pub fn new(manifest_path: &Path) -> Self {
+        let mut name = manifest_path.file_name().unwrap_or_default().to_os_string();
+        name.push(".locks");
+        RepositoryLocks {
+            dir: manifest_path.with_file_name(name),
+        }
+    }
+
+    /// Lists the lock files with their contents, which are `None` if a file can not be read
+    pub fn list(&self) -> Result<Vec<(PathBuf, Option<LockInfo>)>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut locks = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            // temporary files of locks that are being written are skipped
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                let info = fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|json| serde_json::from_str(&json).ok());
+                locks.push((path, info));
+            }
+        }
+        Ok(locks)
+    }
+
+    /// Takes a lock, fails with [`BackrubError::RepositoryLocked`] if a conflicting one is held
+    ///
+    /// Stale locks are removed. The lock is refreshed until the returned guard is dropped.
+    pub fn acquire(&self, kind: LockKind) -> Result<RepositoryLock> {
+        fs::create_dir_all(&self.dir)?;
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        let name: String = id.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let path = self.dir.join(format!("{}.json", name));
+
+        // the lock is written before looking at the others, so of two processes that take
+        // conflicting locks at the same time at least one sees the other
+        let info = LockInfo::new(kind);
+        write_file_atomic(&path, serde_json::to_string(&info)?.as_bytes())?;
+        let lock = RepositoryLock::new(path, info);
+
+        for (other_path, other) in self.list()? {
+            if other_path == lock.path {
+                continue;
+            }
+            match other {
+                Some(other) if other.is_stale() => remove_lock_file(&other_path)?,
+                Some(other) if !other.conflicts_with(kind) => {}
+                Some(other) => return Err(BackrubError::RepositoryLocked(other.to_string()).into()),
+                // unreadable lock files are only removed by breaking the locks
+                None => {
+                    return Err(BackrubError::RepositoryLocked(format!(
+                        "unreadable lock file \"{}\"",
+                        other_path.display()
+                    ))
+                    .into())
+                }
+            }
+        }
+
+        Ok(lock)
+    }
+
+    /// Removes the stale locks, or all locks with `all`, and returns the removed ones
+    ///
+    /// Only break the locks of processes that are known to be gone, otherwise a process could
+    /// change the repository while another one does.
+    pub fn break_locks(&self, all: bool) -> Result<Vec<(PathBuf, Option<LockInfo>)>> {
+        let mut removed = Vec::new();
+        for (path, info) in self.list()? {
+            if all || info.as_ref().is_some_and(LockInfo::is_stale) {
+                remove_lock_file(&path)?;
+                removed.push((path, info));
+            }
+        }
+        Ok(removed)
+    }
+}
+
+fn remove_lock_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// A held lock, it is released when the guard is dropped
+#[derive(Debug)]
+pub struct RepositoryLock {
+    path: PathBuf,
+    kind: LockKind,
+    /// Dropped to stop the refresher
+    stop: Option<mpsc::Sender<()>>,
+    refresher: Option<thread::JoinHandle<()>>,
+}
+
+impl RepositoryLock {
+    /// Starts refreshing the lock written to `path`
+    fn new(path: PathBuf, mut info: LockInfo) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let lock_path = path.clone();
+        let kind = info.kind;
+        let refresher = thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(LOCK_REFRESH_INTERVAL)
+            {
+                // a broken lock is not taken again
+                if !lock_path.exists() {
+                    break;
+                }
+                info.timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+                // a failed refresh is tried again, the lock only gets stale after several
+                if let Ok(json) = serde_json::to_string(&info) {
+                    let _ = write_file_atomic(&lock_path, json.as_bytes());
+                }
+            }
+        });
+
+        RepositoryLock {
+            path,
+            kind,
+            stop: Some(stop),
+            refresher: Some(refresher),
+        }
+    }
+
+    pub fn kind(&self) -> LockKind {
+        self.kind
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for RepositoryLock {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(refresher) = self.refresher.take() {
+            let _ = refresher.join();
+        }
+        // a lock that can not be removed gets stale once this process is gone
+        let _ = remove_lock_file(&self.path);
+    }
+}
@@ -1,6 +1,8 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     env, fs,
     io::prelude::*,
+    os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
 };
 
@@ -9,13 +11,22 @@ use async_std::{
     sync::Mutex,
 };
 use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use chrono::{SecondsFormat, Utc};
 use futures::executor::block_on;
 use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
 
-use crate::utils::chunk_and_hash;
+use crate::utils::{chunk_and_hash, remove_stale_temp_files, write_file_atomic};
 
 use super::db::*;
 use super::error::*;
+use super::lock::*;
+use super::pack::*;
+use super::password::*;
+use super::recovery::*;
+use super::retention::*;
+use super::state::*;
+use super::storage::*;
 use super::structs::*;
 use super::traits::*;
 use super::*;
@@ -23,6 +34,9 @@ use super::*;
 const KB: u64 = 1024;
 const MB: u64 = 1024 * KB;
 const GB: u64 = 1024 * MB;
+/// Contexts of the keys of the files cache, derived from a key of [`CryptoKeys`]
+const FILES_CACHE_ENC_CONTEXT: &str = "backrub files cache encryption v1";
+const FILES_CACHE_HASH_CONTEXT: &str = "backrub files cache path hash v1";
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct BackupManagerConf {
@@ -31,6 +45,12 @@ pub struct BackupManagerConf {
     manifest_path: PathBuf,
     chunker_conf: ChunkerConf,
     argon2_conf: Argon2Conf,
+    trusted_state_dir: Option<PathBuf>,
+    /// Seal backups to a key pair, so write-only keys can be handed out, see
+    /// [`BackupManager::export_write_only_keys`]
+    write_only_mode: bool,
+    compression: Compression,
+    storage: StorageConf,
 }
 
 impl Default for BackupManagerConf {
@@ -53,21 +73,49 @@ impl Default for BackupManagerConf {
             maximum_chunk_size: 64 * MB,
         };
 
-        let argon2_conf = Argon2Conf {
-            threads: 4,
-            mem_cost: 2 * 1024 * 1024 * 1024,
-            time_cost: 2,
-            variant: argon2::Variant::Argon2id.as_u32(),
-            version: argon2::Version::Version13.as_u32(),
+        return BackupManagerConf {
+            chunk_root_dir,
+            db_path,
+            manifest_path,
+            chunker_conf,
+            argon2_conf: Argon2Conf::default(),
+            trusted_state_dir: TrustedStateStore::default_dir(),
+            write_only_mode: false,
+            compression: Compression::default(),
+            storage: StorageConf::default(),
         };
+    }
+}
 
-        return BackupManagerConf {
+impl BackupManagerConf {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        chunk_root_dir: PathBuf,
+        db_path: PathBuf,
+        manifest_path: PathBuf,
+        chunker_conf: ChunkerConf,
+        argon2_conf: Argon2Conf,
+        trusted_state_dir: Option<PathBuf>,
+        write_only_mode: bool,
+        compression: Compression,
+    ) -> Self {
+        BackupManagerConf {
             chunk_root_dir,
             db_path,
             manifest_path,
             chunker_conf,
             argon2_conf,
-        };
+            trusted_state_dir,
+            write_only_mode,
+            compression,
+            storage: StorageConf::default(),
+        }
+    }
+
+    /// Stores the chunk data in `storage` instead of files below the chunk root directory
+    pub fn with_storage(mut self, storage: StorageConf) -> Self {
+        self.storage = storage;
+        self
     }
 }
 
@@ -75,254 +123,1921 @@ impl Default for BackupManagerConf {
 pub struct BackupManager {
     inode_db: InodeDb,
     chunk_db: ChunkDb,
+    backup_db: BackupDb,
+    files_cache: FilesCache,
     manifest: Manifest,
-    keys: CryptoKeys,
-    sig_key: Key256,
+    manifest_path: PathBuf,
+    keys: Locked<CryptoKeys>,
     database: sled::Db,
+    trusted_state: Option<TrustedStateStore>,
+    /// Name of the repository in the [`TrustedStateStore`], see [`CryptoKeys::repository_id`]
+    repository_id: Hash256,
+    /// [`Manifest::digest`] of the manifest on disk, `None` until a new repository is written
+    manifest_digest: Option<Hash256>,
+    /// Keys of the repository key pair, `None` unless the repository is in write-only mode
+    sealing: Option<SealingKeys>,
+    /// Where the chunk data is stored, opened from [`Manifest::storage`]
+    storage: Box<dyn StorageBackend>,
+    /// Pack new chunks are appended to, `None` until the first chunk is written
+    pack_writer: Option<PackWriter>,
+    locks: RepositoryLocks,
 }
 
 impl BackupManager {
+    /// Opens an existing repository, `secret` is a password or a key from a [`PasswordSource`]
+    ///
+    /// With [`Secret::WriteOnly`] the repository is opened by a write-only client, which can only
+    /// create backups.
     pub fn initialize_backup_manager(
         manifest_path: &Path,
-        password: &str,
+        secret: impl Into<Secret>,
     ) -> Result<BackupManager> {
-        let manifest = fs::read_to_string(manifest_path)?;
-
-        let manifest: SignedManifest = serde_json::from_str(&manifest)?;
-
-        let mut crypto_root = argon2::hash_raw(
-            password.as_bytes(),
-            &manifest.get_salt(),
-            &manifest.get_argon2config()?,
-        )?;
-
-        let sig_key: Vec<u8> = crypto_root.drain(..KEY_SIZE).collect();
-        let sig_key = Key256::try_from(sig_key.as_slice())?;
+        Self::initialize_backup_manager_with_conf(manifest_path, secret, &OpenConf::default())
+    }
 
-        let manifest = manifest.verify(&sig_key)?;
+    /// Opens an existing repository like [`Self::initialize_backup_manager`] with options
+    ///
+    /// Returns [`BackrubError::ManifestRollback`] if the manifest is older than the one this client
+    /// saw last, according to the [`TrustedStateStore`] in [`OpenConf::trusted_state_dir`].
+    ///
+    /// If the manifest can not be read or verified, the previous one is used from the copy at
+    /// [`manifest_backup_path`]. Besides manifests the trusted state accepts anyway, that is only
+    /// the one the manifest this client saw last replaced.
+    pub fn initialize_backup_manager_with_conf(
+        manifest_path: &Path,
+        secret: impl Into<Secret>,
+        conf: &OpenConf,
+    ) -> Result<BackupManager> {
+        let secret = secret.into();
+        let (manifest, keys, from_backup) = match read_manifest(manifest_path, &secret) {
+            Ok((manifest, keys)) => (manifest, keys, false),
+            // a changed or revoked password may still unlock the previous manifest
+            Err(e @ Error::BackrubError(BackrubError::WrongPassword)) => return Err(e),
+            // a damaged manifest is replaced by the one it superseded
+            Err(e) => match read_manifest(&manifest_backup_path(manifest_path), &secret) {
+                Ok((manifest, keys)) => (manifest, keys, true),
+                Err(_) => return Err(e),
+            },
+        };
 
         // Only now we are sure that no tapering occured in manifest!
 
-        let key_encryption_keys: Vec<u8> = crypto_root.drain(..CRYPTO_KEYS_SIZE).collect();
-        let key_encryption_keys =
-            <[u8; CRYPTO_KEYS_SIZE]>::try_from(key_encryption_keys.as_slice())?;
-        let key_encryption_keys = KeyEncryptionKeys::from(key_encryption_keys);
-
-        let keys = manifest.keys.decrypt(key_encryption_keys);
+        // but it could still be an older copy
+        let repository_id = match &secret {
+            // the manifest signature key of write-only clients is random
+            Secret::WriteOnly(write_only_keys) => write_only_keys.repository_id(),
+            _ => keys.repository_id(),
+        };
+        let trusted_state = conf.trusted_state_dir.clone().map(TrustedStateStore::new);
+        match &trusted_state {
+            Some(trusted_state) if from_backup => {
+                trusted_state.check_previous(&repository_id, &manifest)?
+            }
+            Some(trusted_state) => trusted_state.check(&repository_id, &manifest)?,
+            None => {}
+        }
+        let manifest_digest = manifest.digest();
+
+        let sealing = match (&manifest.key_pair, &secret) {
+            (None, Secret::WriteOnly(_)) => return Err(BackrubError::NoKeyPair.into()),
+            (None, _) => None,
+            (Some(key_pair), Secret::WriteOnly(write_only_keys)) => {
+                if key_pair.public_key != write_only_keys.public_key {
+                    return Err(BackrubError::KeyPairMismatch.into());
+                }
+                Some(SealingKeys::new(key_pair.public_key, None))
+            }
+            (Some(key_pair), _) => Some(SealingKeys::new(
+                key_pair.public_key,
+                Some(key_pair.secret_key(&keys)?),
+            )),
+        };
+        // migrations need all keys
+        if manifest.format_version != MANIFEST_FORMAT_VERSION
+            && sealing.as_ref().is_some_and(|sealing| !sealing.can_open())
+        {
+            return Err(BackrubError::WriteOnlyAccess.into());
+        }
 
         // read database
-        let db: sled::Db = sled::open(manifest.db_path.clone())?;
+        let db: sled::Db = open_database(&manifest.db_path)?;
         if !db.was_recovered() {
             return Err(BackrubError::SledDbDidNotExist(manifest.db_path).into());
         }
 
         let inode_tree = db.open_tree(b"inodes")?;
         let chunk_tree = db.open_tree(b"chunks")?;
+        let backup_tree = db.open_tree(b"backups")?;
+
+        if manifest.format_version < 3 {
+            // format version 2 encrypted the database entries without associated data
+            add_associated_data_to_tree(
+                &inode_tree,
+                &keys.inode_enc_key,
+                ObjectType::InodeDbEntry,
+            )?;
+            add_associated_data_to_tree(
+                &chunk_tree,
+                &keys.chunk_enc_key,
+                ObjectType::ChunkDbEntry,
+            )?;
+            add_associated_data_to_tree(&backup_tree, &keys.backup_enc_key, ObjectType::Backup)?;
+        }
+        if manifest.format_version < WRITE_ONLY_SIGNATURE_FORMAT_VERSION {
+            // the cache was encrypted with the inode keys, which write-only clients know
+            db.open_tree(b"files_cache")?.clear()?;
+        }
 
-        let inode_db = InodeDb::new(inode_tree, keys.inode_enc_key, keys.inode_hash_key)?;
-
-        let chunk_db = ChunkDb::restore(
-            chunk_tree,
-            keys.chunk_enc_key,
-            manifest.chunk_db_state.clone(),
+        let inode_db = InodeDb::with_sealing(
+            inode_tree,
+            keys.inode_enc_key.clone(),
+            keys.inode_hash_key.clone(),
+            sealing.clone(),
         )?;
+        let backup_db =
+            BackupDb::with_sealing(backup_tree, keys.backup_enc_key.clone(), sealing.clone())?;
+        let write_only = sealing.as_ref().is_some_and(|sealing| !sealing.can_open());
+        let files_cache = open_files_cache(&db, &keys, write_only)?;
+
+        // the file names handed out since the previous manifest are only known to the database
+        let chunk_db = match from_backup {
+            true => ChunkDb::rebuild(chunk_tree, keys.chunk_enc_key.clone())?,
+            false => ChunkDb::restore(
+                chunk_tree,
+                keys.chunk_enc_key.clone(),
+                manifest.chunk_db_state.clone(),
+            )?,
+        };
+        let storage = manifest.storage.open(&manifest.chunk_root_dir)?;
+        // leftovers of writers that crashed while replacing the manifest or a chunk file
+        let manifest_dir = match manifest_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        remove_stale_temp_files(manifest_dir, false)?;
+        storage.remove_stale_temp_files()?;
 
-        let manager = BackupManager {
+        let mut manager = BackupManager {
             inode_db: inode_db,
             chunk_db: chunk_db,
+            backup_db,
+            files_cache,
             manifest,
-            keys,
-            sig_key,
+            manifest_path: manifest_path.to_path_buf(),
+            keys: Locked::new(keys),
             database: db,
+            trusted_state,
+            repository_id,
+            manifest_digest: Some(manifest_digest),
+            sealing,
+            storage,
+            pack_writer: None,
+            locks: RepositoryLocks::new(manifest_path),
         };
 
-        Ok(manager)
-    }
-
-    pub fn new(config: BackupManagerConf, password: &str) -> Result<BackupManager> {
-        let mut salt = [0u8; SALT_SIZE];
-        OsRng.fill_bytes(&mut salt);
+        let _lock = match manager.manifest.format_version != MANIFEST_FORMAT_VERSION {
+            true => Some(manager.locks.acquire(LockKind::Exclusive)?),
+            false => None,
+        };
+        if manager.manifest.format_version < 3 {
+            manager.add_associated_data_to_chunk_files()?;
+        }
 
-        let mut crypto_root = argon2::hash_raw(
-            password.as_bytes(),
-            &salt,
-            &config.argon2_conf.as_argon2config()?,
-        )?;
+        if manager.manifest.format_version != MANIFEST_FORMAT_VERSION {
+            // only bump the version once all data is migrated, so an interrupted migration resumes
+            manager.manifest.format_version = MANIFEST_FORMAT_VERSION;
+            manager.database.flush()?;
+            manager.write_manifet()?;
+        }
+        manager.record_trusted_state()?;
 
-        // Derive signature key
-        let sig_key: Vec<u8> = crypto_root.drain(..KEY_SIZE).collect();
-        let sig_key = Key256::try_from(sig_key.as_slice())?;
+        Ok(manager)
+    }
 
-        // Derive keys
-        let key_encryption_keys: Vec<u8> = crypto_root.drain(..CRYPTO_KEYS_SIZE).collect();
-        let key_encryption_keys =
-            <[u8; CRYPTO_KEYS_SIZE]>::try_from(key_encryption_keys.as_slice())?;
-        let key_encryption_keys = KeyEncryptionKeys::from(key_encryption_keys);
+    /// Creates a new repository, `secret` is a password or a key from a [`PasswordSource`]
+    pub fn new(config: BackupManagerConf, secret: impl Into<Secret>) -> Result<BackupManager> {
+        config.argon2_conf.validate()?;
+        config.compression.validate()?;
+        let storage = config.storage.open(&config.chunk_root_dir)?;
+        let locks = RepositoryLocks::new(&config.manifest_path);
 
         let keys = CryptoKeys::new();
 
-        let enc_keys = keys.encrypt(key_encryption_keys);
+        let key_slot = new_key_slot(&keys, "default", &secret.into(), config.argon2_conf)?;
+
+        let (key_pair, sealing) = if config.write_only_mode {
+            let key_pair = RepositoryKeyPair::generate(&keys)?;
+            let sealing = SealingKeys::new(key_pair.public_key, Some(key_pair.secret_key(&keys)?));
+            (Some(key_pair), Some(sealing))
+        } else {
+            (None, None)
+        };
 
         // create database
-        let db: sled::Db = sled::open(config.clone().db_path)?;
+        let db: sled::Db = open_database(&config.db_path)?;
         if db.was_recovered() {
             return Err(BackrubError::SledDbAlreadyExists(config.db_path).into());
         }
 
-        // setup inode and chunk databases
+        // setup inode, chunk and backup databases
         let inode_tree = db.open_tree(b"inodes")?;
         let chunk_tree = db.open_tree(b"chunks")?;
+        let backup_tree = db.open_tree(b"backups")?;
 
-        let inode_db = InodeDb::new(inode_tree, keys.inode_enc_key, keys.inode_hash_key)?;
-        let chunk_db = ChunkDb::new(chunk_tree, keys.chunk_enc_key)?;
+        let inode_db = InodeDb::with_sealing(
+            inode_tree,
+            keys.inode_enc_key.clone(),
+            keys.inode_hash_key.clone(),
+            sealing.clone(),
+        )?;
+        let chunk_db = ChunkDb::new(chunk_tree, keys.chunk_enc_key.clone())?;
+        let backup_db =
+            BackupDb::with_sealing(backup_tree, keys.backup_enc_key.clone(), sealing.clone())?;
+        let files_cache = open_files_cache(&db, &keys, false)?;
 
         // create Manifest
         let manifest = Manifest {
-            salt: salt,
+            format_version: MANIFEST_FORMAT_VERSION,
             chunk_root_dir: config.chunk_root_dir,
             db_path: config.db_path,
             version: env!("CARGO_PKG_VERSION").to_string(),
             chunker_conf: config.chunker_conf,
-            keys: enc_keys,
-            argon2_conf: config.argon2_conf,
+            key_slots: vec![key_slot],
             chunk_db_state: chunk_db.state.clone(),
+            generation: 0,
+            previous_manifest_hash: Hash256::default(),
+            key_pair,
+            compression: config.compression,
+            max_pack_size: DEFAULT_MAX_PACK_SIZE,
+            storage: config.storage,
         };
 
         // create BackupManager
-        let manager = BackupManager {
+        let repository_id = keys.repository_id();
+        let mut manager = BackupManager {
             inode_db: inode_db,
             chunk_db: chunk_db,
+            backup_db,
+            files_cache,
             manifest,
-            keys,
-            sig_key,
+            manifest_path: config.manifest_path,
+            keys: Locked::new(keys),
             database: db,
+            trusted_state: config.trusted_state_dir.map(TrustedStateStore::new),
+            repository_id,
+            manifest_digest: None,
+            sealing,
+            storage,
+            pack_writer: None,
+            locks,
         };
 
         // write Manifest
-        manager.write_manifet(config.manifest_path.as_path())?;
+        manager.write_manifet()?;
 
         Ok(manager)
     }
 
-    fn write_manifet(&self, manifest_path: &Path) -> Result<()> {
+    /// Writes a new manifest for the existing database and chunk directory of `config`
+    ///
+    /// The keys come from a [`RecoveryKey`], the only key slot is for `secret`.
+    /// Whether the repository is in write-only mode is taken from the recovery key,
+    /// not from `config`.
+    /// If the trusted state directory knows the repository, the manifest continues its
+    /// generations, so clients that saw the lost manifest accept the new one.
+    pub fn recover(
+        config: BackupManagerConf,
+        recovery_key: &RecoveryKey,
+        secret: impl Into<Secret>,
+    ) -> Result<BackupManager> {
+        config.argon2_conf.validate()?;
+        config.compression.validate()?;
+        let storage = config.storage.open(&config.chunk_root_dir)?;
+        let locks = RepositoryLocks::new(&config.manifest_path);
+        if config.manifest_path.exists() {
+            return Err(BackrubError::ManifestAlreadyExists(config.manifest_path).into());
+        }
+
+        let keys = recovery_key.keys.clone();
+        let key_slot = new_key_slot(&keys, "default", &secret.into(), config.argon2_conf)?;
+
+        let (key_pair, sealing) = match &recovery_key.repository_secret_key {
+            Some(secret_key) => {
+                let key_pair = RepositoryKeyPair::from_secret_key(&keys, secret_key)?;
+                let sealing = SealingKeys::new(key_pair.public_key, Some(secret_key.clone()));
+                (Some(key_pair), Some(sealing))
+            }
+            None => (None, None),
+        };
+
+        // read database, the self tests fail if the keys do not belong to it
+        let db: sled::Db = open_database(&config.db_path)?;
+        if !db.was_recovered() {
+            return Err(BackrubError::SledDbDidNotExist(config.db_path).into());
+        }
+
+        let inode_db = InodeDb::with_sealing(
+            db.open_tree(b"inodes")?,
+            keys.inode_enc_key.clone(),
+            keys.inode_hash_key.clone(),
+            sealing.clone(),
+        )?;
+        let chunk_db = ChunkDb::rebuild(db.open_tree(b"chunks")?, keys.chunk_enc_key.clone())?;
+        let backup_db = BackupDb::with_sealing(
+            db.open_tree(b"backups")?,
+            keys.backup_enc_key.clone(),
+            sealing.clone(),
+        )?;
+        // the cache may be of an older version than the lost manifest, it is only a cache
+        db.open_tree(b"files_cache")?.clear()?;
+        let files_cache = open_files_cache(&db, &keys, false)?;
+
+        let repository_id = keys.repository_id();
+        let trusted_state = config.trusted_state_dir.map(TrustedStateStore::new);
+        let last_seen = match &trusted_state {
+            Some(trusted_state) => trusted_state.load(&repository_id)?,
+            None => None,
+        };
+
+        let manifest = Manifest {
+            format_version: MANIFEST_FORMAT_VERSION,
+            chunk_root_dir: config.chunk_root_dir,
+            db_path: config.db_path,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            chunker_conf: config.chunker_conf,
+            key_slots: vec![key_slot],
+            chunk_db_state: chunk_db.state.clone(),
+            generation: last_seen.map_or(0, |state| state.generation),
+            previous_manifest_hash: Hash256::default(),
+            key_pair,
+            compression: config.compression,
+            max_pack_size: DEFAULT_MAX_PACK_SIZE,
+            storage: config.storage,
+        };
+
+        let mut manager = BackupManager {
+            inode_db,
+            chunk_db,
+            backup_db,
+            files_cache,
+            manifest,
+            manifest_path: config.manifest_path,
+            keys: Locked::new(keys),
+            database: db,
+            trusted_state,
+            repository_id,
+            manifest_digest: last_seen.map(|state| state.manifest_digest),
+            sealing,
+            storage,
+            pack_writer: None,
+            locks,
+        };
+
+        manager.write_manifet()?;
+
+        Ok(manager)
+    }
+
+    fn write_manifet(&mut self) -> Result<()> {
+        // write-only clients do not know the manifest signature key
+        self.require_full_access()?;
+
         // copy manifest
         let mut manifest = self.manifest.clone();
 
         // update manifest
         manifest.chunk_db_state = self.chunk_db.state.clone();
+        manifest.generation = self.manifest.generation + 1;
+        manifest.previous_manifest_hash = self.manifest_digest.unwrap_or_default();
 
         // sign manifest
-        let signed = manifest.sign(&self.sig_key)?;
+        let signed = manifest.sign(&self.keys.manifest_sig_key)?;
 
         // serialize
         let manifest_json = serde_json::to_string(&signed)?;
 
-        // write manifest
-        let mut file = fs::File::create(manifest_path)?;
-        file.write_all(manifest_json.as_bytes())?;
+        // keep the replaced manifest, unless it is damaged or not the one that was opened
+        match fs::read(&self.manifest_path) {
+            Ok(previous) => {
+                let intact = serde_json::from_slice::<SignedManifest>(&previous)
+                    .is_ok_and(|previous| Some(previous.manifest.digest()) == self.manifest_digest);
+                if intact {
+                    write_file_atomic(&manifest_backup_path(&self.manifest_path), &previous)?;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        write_file_atomic(&self.manifest_path, manifest_json.as_bytes())?;
+
+        self.manifest_digest = Some(signed.manifest.digest());
+        self.manifest = signed.manifest;
+        self.record_trusted_state()
+    }
+
+    /// Remembers the current manifest as the last one this client has seen
+    fn record_trusted_state(&self) -> Result<()> {
+        if let Some(trusted_state) = &self.trusted_state {
+            trusted_state.record(&self.repository_id, &self.manifest)?;
+        }
+        Ok(())
+    }
+
+    /// Changes the password of the repository
+    ///
+    /// The key slot unlocked by `old_password` is rewrapped with keys derived from the new
+    /// password and a fresh salt, so all chunks and database entries stay valid.
+    /// If `argon2_conf` is `None` the current Argon2 parameters of the slot are kept.
+    pub fn change_password(
+        &mut self,
+        old_password: impl Into<Secret>,
+        new_password: impl Into<Secret>,
+        argon2_conf: Option<Argon2Conf>,
+    ) -> Result<()> {
+        self.require_full_access()?;
+        let (index, _) = unlock_key_slots(&self.manifest.key_slots, &old_password.into())?;
+
+        let mut key_slots = self.manifest.key_slots.clone();
+        let old_slot = &key_slots[index];
+        let argon2_conf = argon2_conf
+            .or(old_slot.argon2_conf)
+            .unwrap_or_else(|| self.default_argon2_conf());
+        key_slots[index] = new_key_slot(
+            &self.keys,
+            &old_slot.label,
+            &new_password.into(),
+            argon2_conf,
+        )?;
+
+        self.replace_key_slots(key_slots)
+    }
+
+    /// Adds a key slot, so `secret` can unlock the repository as well
+    ///
+    /// If `argon2_conf` is `None` the Argon2 parameters of the first password slot are used.
+    /// Key slots for random keys do not use Argon2 at all.
+    pub fn add_key_slot(
+        &mut self,
+        label: &str,
+        secret: impl Into<Secret>,
+        argon2_conf: Option<Argon2Conf>,
+    ) -> Result<()> {
+        self.require_full_access()?;
+        if self
+            .manifest
+            .key_slots
+            .iter()
+            .any(|slot| slot.label == label)
+        {
+            return Err(BackrubError::KeySlotExists(label.to_string()).into());
+        }
+
+        let argon2_conf = argon2_conf.unwrap_or_else(|| self.default_argon2_conf());
+        let mut key_slots = self.manifest.key_slots.clone();
+        key_slots.push(new_key_slot(
+            &self.keys,
+            label,
+            &secret.into(),
+            argon2_conf,
+        )?);
+
+        self.replace_key_slots(key_slots)
+    }
+
+    /// Changes the compression of chunks written from now on
+    ///
+    /// Existing chunks keep their compression, they are readable either way.
+    pub fn set_compression(&mut self, compression: Compression) -> Result<()> {
+        compression.validate()?;
+        let _lock = self.locks.acquire(LockKind::Exclusive)?;
+        let old_compression = std::mem::replace(&mut self.manifest.compression, compression);
+
+        if let Err(e) = self.write_manifet() {
+            self.manifest.compression = old_compression;
+            return Err(e);
+        }
 
         Ok(())
     }
 
-    /// Cerates a new backup
-    pub fn create_backup(&mut self, name: &str, path: &Path, conf: &BackupConf) -> Result<()> {
+    /// Changes the size at which pack files are finished, `0` stores every new chunk in a file of its own
+    ///
+    /// Existing packs and chunk files are kept, [`Self::repack`] moves their chunks into new packs.
+    pub fn set_max_pack_size(&mut self, max_pack_size: u64) -> Result<()> {
+        let _lock = self.locks.acquire(LockKind::Exclusive)?;
+        self.finish_pack()?;
+        let old_max_pack_size = std::mem::replace(&mut self.manifest.max_pack_size, max_pack_size);
+
+        if let Err(e) = self.write_manifet() {
+            self.manifest.max_pack_size = old_max_pack_size;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Lists the key slots of the repository in the order they are tried when unlocking
+    pub fn list_key_slots(&self) -> Vec<KeySlot> {
+        self.manifest.key_slots.clone()
+    }
+
+    /// Removes the key slot labeled `label`
+    ///
+    /// The data keys stay the same, so this only locks out a password whose holder never had
+    /// access to an unlocked repository.
+    pub fn revoke_key_slot(&mut self, label: &str) -> Result<()> {
+        self.require_full_access()?;
+        let mut key_slots = self.manifest.key_slots.clone();
+        let index = key_slots
+            .iter()
+            .position(|slot| slot.label == label)
+            .ok_or_else(|| BackrubError::KeySlotDidNotExist(label.to_string()))?;
+        if key_slots.len() == 1 {
+            return Err(BackrubError::LastKeySlot.into());
+        }
+        key_slots.remove(index);
+
+        self.replace_key_slots(key_slots)
+    }
+
+    /// Exports the keys a write-only client needs to create backups
+    ///
+    /// Only repositories created in write-only mode have the key pair backups are sealed to.
+    /// The keys can not decrypt any backup and full clients reject manifests signed with them.
+    /// Write-only clients do not: the key they verify manifests with can sign them as well, so
+    /// everyone holding the keys can make the other write-only clients accept a manifest.
+    pub fn export_write_only_keys(&self) -> Result<WriteOnlyKeys> {
+        self.require_full_access()?;
+        let key_pair = self
+            .manifest
+            .key_pair
+            .as_ref()
+            .ok_or(BackrubError::NoKeyPair)?;
+        Ok(WriteOnlyKeys::new(&self.keys, key_pair.public_key))
+    }
+
+    /// Exports the keys of the repository for restoring a lost manifest
+    ///
+    /// Print it with [`RecoveryKey::encode`] and store it safely, it decrypts everything.
+    pub fn export_recovery_key(&self) -> Result<RecoveryKey> {
+        self.require_full_access()?;
+        let repository_secret_key = match &self.sealing {
+            Some(sealing) => Some(sealing.secret_key()?.clone()),
+            None => None,
+        };
+        Ok(RecoveryKey::new(
+            (*self.keys).clone(),
+            repository_secret_key,
+        ))
+    }
+
+    /// Lock files of the repository, stale ones can be removed with [`RepositoryLocks::break_locks`]
+    pub fn locks(&self) -> &RepositoryLocks {
+        &self.locks
+    }
+
+    /// Whether the repository was opened by a write-only client
+    pub fn is_write_only(&self) -> bool {
+        self.sealing
+            .as_ref()
+            .is_some_and(|sealing| !sealing.can_open())
+    }
+
+    /// Fails with [`BackrubError::WriteOnlyAccess`] for write-only clients
+    fn require_full_access(&self) -> Result<()> {
+        if self.is_write_only() {
+            return Err(BackrubError::WriteOnlyAccess.into());
+        }
+        Ok(())
+    }
+
+    /// Argon2 parameters of the first password slot
+    fn default_argon2_conf(&self) -> Argon2Conf {
+        self.manifest
+            .key_slots
+            .iter()
+            .find_map(|slot| slot.argon2_conf)
+            .unwrap_or_default()
+    }
+
+    /// Writes the manifest with new key slots, keeps the old ones if that fails
+    fn replace_key_slots(&mut self, key_slots: Vec<KeySlot>) -> Result<()> {
+        let _lock = self.locks.acquire(LockKind::Exclusive)?;
+        let old_key_slots = std::mem::replace(&mut self.manifest.key_slots, key_slots);
+
+        if let Err(e) = self.write_manifet() {
+            self.manifest.key_slots = old_key_slots;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Creates a new backup of the directory `path` and stores it under `name`
+    ///
+    /// Returns the id of the new [`Backup`]
+    pub fn create_backup(&mut self, name: &str, path: &Path, conf: &BackupConf) -> Result<Hash256> {
         if !path.is_dir() {
             return Err(BackrubError::BackupRootMustBeDir(path.to_path_buf()).into());
         }
+        // repairing has to read the existing chunks
+        if conf.repair {
+            self.require_full_access()?;
+        }
+        let _lock = self.locks.acquire(LockKind::Exclusive)?;
+
+        let mut id = Hash256::default();
+        OsRng.fill_bytes(id.as_mut());
+
+        // store an unfinished record first, so interrupted backups can be found
+        let mut backup = Backup {
+            id,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            name: name.to_string(),
+            root: Hash256::default(),
+            finished: false,
+            source_host: whoami::fallible::hostname().unwrap_or_default(),
+            source_path: fs::canonicalize(path)?,
+            stats: BackupStats::default(),
+        };
+        self.backup_db.insert(&backup)?;
+
+        // the references are applied together with the finished record, the packs of a failed
+        // backup are stored but not referenced and are removed by repacking
+        let mut batch = RefCountBatch::default();
+        // the files cache is keyed by absolute paths
+        let path = backup.source_path.clone();
+        let root = self.backup_dir(
+            &mut batch,
+            &path,
+            &path,
+            conf,
+            &mut backup.stats,
+            &mut BTreeSet::new(),
+        );
+        // the chunks have to be stored before the database references them
+        self.finish_pack()?;
+        backup.root = root?;
+        backup.finished = true;
+        batch.insert_backup(backup);
+        batch.commit(&self.inode_db, &mut self.chunk_db, &self.backup_db)?;
+
+        // make sure everything is on disk before the manifest references the new chunk db state
+        self.database.flush()?;
+        // the chunk db state of write-only clients is rebuilt from the database on the next open
+        if !self.is_write_only() {
+            self.write_manifet()?;
+        }
 
-        self.backup_dir(path, conf)?;
+        Ok(id)
+    }
 
-        todo!("create backup db entry")
+    /// Gets a stored [`Backup`] record by its id
+    pub fn get_backup(&self, id: &Hash256) -> Result<Option<Backup>> {
+        self.backup_db.get(id)
     }
 
-    /// performs all backup operations for a directory
-    fn backup_dir(&mut self, path: &Path, conf: &BackupConf) -> Result<(Vec<Inode>)> {
-        let dir_iter = fs::read_dir(path)?;
+    /// Gets all backups with the given name sorted by their timestamp
+    pub fn get_backups_by_name(&self, name: &str) -> Result<Vec<Backup>> {
+        let mut backups = self.backup_db.get_all()?;
+        backups.retain(|backup| backup.name == name);
+        Ok(backups)
+    }
+
+    /// Lists all backups sorted by their timestamp
+    pub fn list_backups(&self) -> Result<Vec<Backup>> {
+        self.backup_db.get_all()
+    }
+
+    /// Deletes a backup and returns its record
+    ///
+    /// The reference counts of all inodes and chunks of the backup are decremented,
+    /// chunk files that are no longer referenced are removed. The space of packed chunks is
+    /// reclaimed by [`Self::repack`].
+    pub fn delete_backup(&mut self, id: &Hash256) -> Result<Backup> {
+        self.require_full_access()?;
+        let _lock = self.locks.acquire(LockKind::Exclusive)?;
+        self.remove_backup(id)
+    }
+
+    /// Deletes a backup while the exclusive lock is held
+    ///
+    /// The record and all references are removed in one transaction, afterwards the chunk files
+    /// that are no longer referenced are removed.
+    fn remove_backup(&mut self, id: &Hash256) -> Result<Backup> {
+        let backup = self
+            .backup_db
+            .get(id)?
+            .ok_or(BackrubError::BackupDidNotExist(*id))?;
+
+        let mut batch = RefCountBatch::default();
+        // the inodes of interrupted backups are not referenced by anything
+        if backup.finished {
+            self.release_inode(&mut batch, &backup.root)?;
+        }
+        batch.remove_backup(id);
+
+        // packed chunks stay in their pack until it is rewritten by repacking
+        for location in batch.commit(&self.inode_db, &mut self.chunk_db, &self.backup_db)? {
+            if !location.is_packed() {
+                self.storage.delete(&location.file_name)?;
+            }
+        }
+
+        self.database.flush()?;
+        self.write_manifet()?;
+
+        Ok(backup)
+    }
 
-        let mut result = Vec::<Inode>::new();
+    /// Applies a retention policy to all backups
+    ///
+    /// With `dry_run` set only the report is returned,
+    /// otherwise all backups that are not kept are deleted like [`Self::delete_backup`] does.
+    pub fn prune(&mut self, policy: &RetentionPolicy, dry_run: bool) -> Result<RetentionReport> {
+        self.require_full_access()?;
+        let _lock = match dry_run {
+            true => self.locks.acquire(LockKind::Shared)?,
+            false => self.locks.acquire(LockKind::Exclusive)?,
+        };
+        let report = policy.evaluate(self.backup_db.get_all()?, Utc::now())?;
 
-        struct DEntry {
-            path: PathBuf,
-            meta: fs::Metadata,
+        if !dry_run {
+            for backup in report.forget.iter() {
+                self.remove_backup(&backup.id)?;
+            }
         }
 
-        let mut dirs = Vec::<PathBuf>::new();
-        let mut files = Vec::<DEntry>::new();
-        let mut slinks = Vec::<DEntry>::new();
+        Ok(report)
+    }
+
+    /// Reclaims the space of released chunks by copying the referenced ones into new packs
+    ///
+    /// A pack is rewritten if its released chunks take at least `min_unused_percent` of its size,
+    /// chunk files that are not packed are always moved into packs. Files without referenced
+    /// chunks are removed. Chunks are copied without decrypting them.
+    pub fn repack(&mut self, min_unused_percent: u8) -> Result<RepackReport> {
+        self.require_full_access()?;
+        let _lock = self.locks.acquire(LockKind::Exclusive)?;
+        self.finish_pack()?;
+        let mut report = RepackReport::default();
+        let mut batch = RefCountBatch::default();
+
+        let mut files = BTreeMap::<PathBuf, Vec<(Hash256, ChunkLocation)>>::new();
+        for (chunk_id, (_ref_count, location)) in self.chunk_db.get_mappings()? {
+            files
+                .entry(location.file_name.clone())
+                .or_default()
+                .push((chunk_id, location));
+        }
+
+        // files that are not referenced and whose names are not handed out again
+        let stored = self.storage.list()?;
+        let mut obsolete = Vec::<PathBuf>::new();
+        for file_name in stored.keys() {
+            if FilePathGen::position(file_name).is_some()
+                && !files.contains_key(file_name)
+                && !self.chunk_db.is_released_file_name(file_name)
+            {
+                obsolete.push(file_name.clone());
+            }
+        }
+
+        for (file_name, chunks) in files {
+            // missing files are reported by the repository check
+            let Some(&size) = stored.get(&file_name) else {
+                continue;
+            };
+
+            let rewrite = match chunks[0].1.range {
+                None => self.manifest.max_pack_size > 0,
+                Some(_) => {
+                    let used = chunks
+                        .iter()
+                        .filter_map(|(_, location)| location.range)
+                        .map(|range| range.length)
+                        .sum::<u64>()
+                        + PackIndex::stored_size(self.storage.as_ref(), &file_name);
+                    let unused = size.saturating_sub(used);
+                    unused > 0 && unused * 100 >= size * u64::from(min_unused_percent)
+                }
+            };
+            if !rewrite {
+                continue;
+            }
+
+            for (chunk_id, location) in chunks {
+                let encrypted_chunk = location.read(self.storage.as_ref())?;
+                let location = self.store_chunk(&chunk_id, &encrypted_chunk)?;
+                self.chunk_db
+                    .relocate_batched(&mut batch, &chunk_id, location);
+                report.chunks_moved += 1;
+                report.bytes_written += encrypted_chunk.len() as u64;
+            }
+            report.files_rewritten += 1;
+            obsolete.push(file_name);
+        }
+
+        // the copies have to be stored before the chunks point to them,
+        // and the chunks have to point to them before the old files are removed
+        self.finish_pack()?;
+        batch.commit(&self.inode_db, &mut self.chunk_db, &self.backup_db)?;
+        self.database.flush()?;
+        for file_name in obsolete {
+            report.bytes_removed += stored.get(&file_name).copied().unwrap_or_default();
+            self.storage.delete(&file_name)?;
+            report.files_removed += 1;
+            self.chunk_db.release_file_name(file_name);
+        }
+        self.write_manifet()?;
+
+        Ok(report)
+    }
+
+    /// Checks the repository for consistency
+    ///
+    /// Every problem that is found is collected in the returned [`CheckReport`],
+    /// an `Err` is only returned if the check itself could not be performed.
+    pub fn check(&self, level: CheckLevel) -> Result<CheckReport> {
+        self.require_full_access()?;
+        let _lock = self.locks.acquire(LockKind::Shared)?;
+        let mut report = CheckReport::default();
+
+        // count the real references by walking all backups
+        let mut inode_refs = BTreeMap::<Hash256, RefCount>::new();
+        let mut chunk_refs = BTreeMap::<Hash256, RefCount>::new();
+        for backup in self.backup_db.get_all()? {
+            if backup.finished {
+                self.count_references(&backup.root, &mut inode_refs, &mut chunk_refs, &mut report)?;
+            }
+        }
+
+        let stored = self.storage.list()?;
+        let current_pack = self.pack_writer.as_ref().map(|pack| pack.file_name());
+
+        // compare with the inode database
+        for (key, (ref_count, _inode)) in self.inode_db.get_mappings()? {
+            match inode_refs.remove(&key) {
+                None => report.orphaned_inodes.push(key),
+                Some(real) if real != ref_count => {
+                    report.wrong_inode_ref_counts.push((key, ref_count, real))
+                }
+                Some(_) => {}
+            }
+        }
+
+        // compare with the chunk database and check the chunk files
+        let mut chunk_files = BTreeSet::<PathBuf>::new();
+        let mut packs = BTreeMap::<PathBuf, Vec<(Hash256, PackRange)>>::new();
+        for (key, (ref_count, location)) in self.chunk_db.get_mappings()? {
+            match chunk_refs.remove(&key) {
+                None => report.orphaned_chunks.push(key),
+                Some(real) if real != ref_count => {
+                    report.wrong_chunk_ref_counts.push((key, ref_count, real))
+                }
+                Some(_) => {}
+            }
+
+            if !stored.contains_key(&location.file_name)
+                && Some(location.file_name.as_path()) != current_pack
+            {
+                report
+                    .missing_chunk_files
+                    .push((key, location.file_name.clone()));
+                continue;
+            } else if level == CheckLevel::Data && !self.verify_chunk(&key, &location)? {
+                report
+                    .corrupt_chunks
+                    .push((key, location.file_name.clone()));
+            }
+            if let Some(range) = location.range {
+                packs
+                    .entry(location.file_name.clone())
+                    .or_default()
+                    .push((key, range));
+            }
+            chunk_files.insert(location.file_name);
+        }
+        report.missing_chunks = chunk_refs.into_keys().collect();
+
+        if level == CheckLevel::Data {
+            for (file_name, chunks) in packs {
+                // the index of the current pack is not written yet
+                if Some(file_name.as_path()) == current_pack {
+                    continue;
+                }
+                let index = self.read_pack_index(&file_name);
+                if !index.is_ok_and(|index| chunks.iter().all(|chunk| index.chunks.contains(chunk)))
+                {
+                    report.corrupt_pack_indexes.push(file_name);
+                }
+            }
+        }
+
+        // look for files that do not belong to any chunk
+        for file_name in stored.into_keys() {
+            if chunk_files.contains(&file_name) {
+                continue;
+            }
+            // packs stay when their last chunk is released, until they are repacked
+            if self.read_pack_index(&file_name).is_ok() {
+                report.unused_packs.push(file_name);
+            } else {
+                report.orphaned_chunk_files.push(file_name);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Counts all references to inodes and chunks below the inode `key`
+    fn count_references(
+        &self,
+        key: &Hash256,
+        inode_refs: &mut BTreeMap<Hash256, RefCount>,
+        chunk_refs: &mut BTreeMap<Hash256, RefCount>,
+        report: &mut CheckReport,
+    ) -> Result<()> {
+        let count = inode_refs.entry(*key).or_insert(0);
+        *count += 1;
+
+        match self.inode_db.get_inode(key)? {
+            None => {
+                if *count == 1 {
+                    report.missing_inodes.push(*key);
+                }
+            }
+            Some(Inode::Directory(dir)) => {
+                for key in dir.contents.iter() {
+                    self.count_references(key, inode_refs, chunk_refs, report)?;
+                }
+            }
+            Some(Inode::File(file)) => {
+                for chunk_id in file.chunk_ids.iter() {
+                    *chunk_refs.entry(*chunk_id).or_insert(0) += 1;
+                }
+            }
+            Some(Inode::Symlink(_)) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Reads, decrypts and re-hashes a stored chunk, returns `false` if the chunk is corrupt or missing
+    fn verify_chunk(&self, chunk_id: &Hash256, location: &ChunkLocation) -> Result<bool> {
+        let data = match self.read_stored_chunk(location) {
+            Ok(data) => data,
+            Err(e) => match e.io_error_kind() {
+                Some(std::io::ErrorKind::NotFound) => return Ok(false),
+                // the pack ends before the chunk
+                Some(std::io::ErrorKind::UnexpectedEof) => return Ok(false),
+                _ => return Err(e),
+            },
+        };
+        match self.decrypt_chunk(chunk_id, &data) {
+            Err(_) => Ok(false),
+            Ok(chunk) => Ok(Hash256::from(
+                chunk.data.keyed_hash(&self.keys.chunk_hash_key)?.as_bytes(),
+            ) == *chunk_id),
+        }
+    }
+
+    /// Removes one reference to an inode and everything it references in `batch`
+    ///
+    /// Every backup run increments the reference counts of all inodes and chunks it contains,
+    /// so the references have to be released recursively regardless of the remaining count.
+    fn release_inode(&self, batch: &mut RefCountBatch, key: &Hash256) -> Result<()> {
+        // missing entries are inconsistencies that are reported by the repository check
+        let inode = match self.inode_db.remove_batched(batch, key)? {
+            None => return Ok(()),
+            Some((_ref_count, inode)) => inode,
+        };
+
+        match inode {
+            Inode::Directory(dir) => {
+                for key in dir.contents.iter() {
+                    self.release_inode(batch, key)?;
+                }
+            }
+            Inode::File(file) => {
+                for chunk_id in file.chunk_ids.iter() {
+                    let _ = self.chunk_db.remove_batched(batch, chunk_id)?;
+                }
+            }
+            Inode::Symlink(_) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Performs all backup operations for a directory and returns the hash of its [`Directory`] inode
+    ///
+    /// All paths stored in inodes are relative to `root`.
+    /// `ancestors` holds the `(dev, ino)` pairs of the directories that are being backed up,
+    /// symlinks to one of them are stored as symlinks because following them would never end.
+    fn backup_dir(
+        &mut self,
+        batch: &mut RefCountBatch,
+        root: &Path,
+        path: &Path,
+        conf: &BackupConf,
+        stats: &mut BackupStats,
+        ancestors: &mut BTreeSet<(u64, u64)>,
+    ) -> Result<Hash256> {
+        let dir_iter = fs::read_dir(path)?;
+        let dir_meta = fs::metadata(path)?;
+        ancestors.insert((dir_meta.dev(), dir_meta.ino()));
+
+        let mut contents = Vec::<Hash256>::new();
 
         for entry in dir_iter {
             let entry = entry?;
-            let e_meta = entry.metadata()?;
-            let r_path = entry.path();
+            let e_path = entry.path();
+            let e_meta = match conf.follow_symlinks {
+                // symlinks whose target can not be resolved, like dangling ones, are stored as symlinks
+                true => match fs::metadata(&e_path) {
+                    Ok(meta) if meta.is_dir() && ancestors.contains(&(meta.dev(), meta.ino())) => {
+                        entry.metadata()?
+                    }
+                    Ok(meta) => meta,
+                    Err(_) if entry.file_type()?.is_symlink() => entry.metadata()?,
+                    Err(e) => return Err(e.into()),
+                },
+                false => entry.metadata()?,
+            };
+            let relpath = e_path
+                .strip_prefix(root)
+                .expect("this can not fail because all entries are below root")
+                .to_path_buf();
 
             if e_meta.is_dir() {
-                dirs.push(r_path)
+                contents.push(self.backup_dir(batch, root, &e_path, conf, stats, ancestors)?);
             } else if e_meta.is_file() {
-                files.push(DEntry {
-                    path: r_path,
-                    meta: e_meta,
-                })
+                contents.push(self.backup_file(batch, &e_path, relpath, e_meta, conf, stats)?);
             } else if e_meta.is_symlink() {
-                slinks.push(DEntry {
-                    path: r_path,
-                    meta: e_meta,
-                })
+                let key = self.inode_db.insert_batched(
+                    batch,
+                    Inode::Symlink(Symlink {
+                        target: fs::read_link(&e_path)?,
+                        relpath,
+                        metadata: structs::Metadata::from(e_meta),
+                    }),
+                )?;
+                contents.push(key);
+                stats.symlinks += 1;
             }
         }
 
-        for link in slinks {
-            result.push(Inode::Symlink(Symlink {
-                relpath: link.path.clone(),
-                target: fs::read_link(link.path)?.to_path_buf(),
-                metadata: structs::Metadata::from(link.meta),
-            }));
+        ancestors.remove(&(dir_meta.dev(), dir_meta.ino()));
+        // the order of read_dir is not defined, sort to get a stable inode hash
+        contents.sort();
+
+        let key = self.inode_db.insert_batched(
+            batch,
+            Inode::Directory(Directory {
+                relpath: path
+                    .strip_prefix(root)
+                    .expect("this can not fail because path is below root")
+                    .to_path_buf(),
+                metadata: structs::Metadata::from(dir_meta),
+                contents,
+            }),
+        )?;
+        stats.directories += 1;
+
+        Ok(key)
+    }
+
+    /// Backs up a file and returns the hash of its [`File`](structs::File) inode
+    ///
+    /// Unchanged files are taken from the files cache without reading them
+    fn backup_file(
+        &mut self,
+        batch: &mut RefCountBatch,
+        path: &Path,
+        relpath: PathBuf,
+        meta: fs::Metadata,
+        conf: &BackupConf,
+        stats: &mut BackupStats,
+    ) -> Result<Hash256> {
+        let metadata = structs::Metadata::from(meta);
+
+        // in repair mode every chunk has to be verified, so the cache can not be used
+        let cached = if conf.force_rehash || conf.repair {
+            None
+        } else {
+            self.reuse_cached_file(batch, path, &metadata)?
+        };
+
+        let (chunk_ids, file_hash) = match cached {
+            Some(cached) => {
+                stats.files_skipped += 1;
+                cached
+            }
+            None => {
+                let (chunk_ids, file_hash) =
+                    self.chunk_file(batch, path, metadata.size, conf, stats)?;
+                self.files_cache.insert(
+                    path,
+                    &FilesCacheEntry {
+                        metadata,
+                        chunk_ids: chunk_ids.clone(),
+                        file_hash,
+                    },
+                )?;
+                stats.files_read += 1;
+                (chunk_ids, file_hash)
+            }
+        };
+
+        stats.files += 1;
+        stats.bytes += metadata.size;
+
+        let key = self.inode_db.insert_batched(
+            batch,
+            Inode::File(structs::File {
+                relpath,
+                chunk_ids,
+                metadata,
+                file_hash,
+            }),
+        )?;
+
+        Ok(key)
+    }
+
+    /// Looks up an unchanged file in the files cache and takes new references to its chunks
+    ///
+    /// Returns the chunk ids and the file hash or `None` if the file has to be read
+    fn reuse_cached_file(
+        &mut self,
+        batch: &mut RefCountBatch,
+        path: &Path,
+        metadata: &structs::Metadata,
+    ) -> Result<Option<(Vec<Hash256>, Hash256)>> {
+        let entry = match self.files_cache.get(path)? {
+            Some(entry) if entry.matches(metadata) => entry,
+            _ => return Ok(None),
+        };
+
+        // chunks may have been removed by deleting backups in the meantime
+        for chunk_id in entry.chunk_ids.iter() {
+            if self.chunk_db.get_entry(chunk_id)?.is_none() {
+                return Ok(None);
+            }
+        }
+        for chunk_id in entry.chunk_ids.iter() {
+            let _ = self.chunk_db.insert_batched(batch, chunk_id)?;
         }
 
+        Ok(Some((entry.chunk_ids, entry.file_hash)))
+    }
+
+    /// Chunks a file and writes all new chunks, returns the chunk ids and the file hash
+    fn chunk_file(
+        &mut self,
+        batch: &mut RefCountBatch,
+        path: &Path,
+        size: u64,
+        conf: &BackupConf,
+        stats: &mut BackupStats,
+    ) -> Result<(Vec<Hash256>, Hash256)> {
         use memmap::Mmap;
-        use std::fs::File;
-
-        for file in files {
-            let f = File::open(file.path.clone()).unwrap();
-            let mmap = unsafe { Mmap::map(&f).unwrap() };
-            match chunk_and_hash(
-                &mmap,
-                &self.manifest.chunker_conf,
-                &self.keys.chunk_hash_key,
-                &self.keys.inode_hash_key,
-            ){
-                Ok(res) => {
-                    let (chunks, hash) = res;
-                    result.push(Inode::File(structs::File{
-                        relpath: file.path.clone(),
-                        metadata: structs::Metadata::from(file.meta),
-                        file_hash: Hash256::from(hash.as_bytes()),
-                        chunk_ids: chunks.into_iter().map(|(c, h)| Hash256::from(h.as_bytes())).collect(),
-                    }));
-
-                    for (d, h) in chunks.into_iter(){
-                        match self.chunk_db.insert(&Hash256::from(h.as_bytes())) {
-                            Ok((count, path)) => {
-                                if count == 1 {
-                                    todo!("create chunk file on {:?} with data {:?}",path,d)
-                                }
-                            }
-
-                            Err(e) => { todo!("report error: {:?}", e) }
-                        }
+
+        let f = fs::File::open(path)?;
+        // mapping an empty file is not possible
+        let mmap = if size > 0 {
+            Some(unsafe { Mmap::map(&f)? })
+        } else {
+            None
+        };
+        let data: &[u8] = match &mmap {
+            Some(mmap) => &mmap[..],
+            None => &[],
+        };
+
+        let (chunks, file_hash) = chunk_and_hash(
+            data,
+            &self.manifest.chunker_conf,
+            &self.keys.chunk_hash_key,
+            &self.keys.inode_hash_key,
+        )?;
+
+        let mut chunk_ids = Vec::<Hash256>::with_capacity(chunks.len());
+        for (data, hash) in chunks.iter() {
+            let chunk_id = Hash256::from(hash.as_bytes());
+            match self.chunk_db.insert_batched(batch, &chunk_id)? {
+                None => {
+                    let compression = conf
+                        .compression_policy
+                        .choose(self.manifest.compression, data)?;
+                    if compression != self.manifest.compression {
+                        stats.uncompressed_chunks += 1;
+                    }
+                    let encrypted_chunk = self.encrypt_chunk(&chunk_id, data, compression)?;
+                    let location = self.store_chunk(&chunk_id, &encrypted_chunk)?;
+                    self.chunk_db.insert_new_batched(batch, &chunk_id, location);
+                    stats.new_chunk_bytes += encrypted_chunk.len() as u64;
+                    stats.new_chunks += 1;
+                }
+                // the source data is known to be good, so a broken chunk can be recreated from it
+                Some((_ref_count, location))
+                    if conf.repair && !self.verify_chunk(&chunk_id, &location)? =>
+                {
+                    let compression = conf
+                        .compression_policy
+                        .choose(self.manifest.compression, data)?;
+                    let encrypted_chunk = self.encrypt_chunk(&chunk_id, data, compression)?;
+                    if location.is_packed() {
+                        // the rest of the pack may be intact, so the chunk is appended elsewhere
+                        let location = self.store_chunk(&chunk_id, &encrypted_chunk)?;
+                        self.chunk_db.relocate_batched(batch, &chunk_id, location);
+                    } else {
+                        self.storage.put(&location.file_name, &encrypted_chunk)?;
+                    }
+                    stats.repaired_chunks += 1;
+                }
+                Some(_) => {}
+            }
+            chunk_ids.push(chunk_id);
+        }
+
+        Ok((chunk_ids, Hash256::from(file_hash.as_bytes())))
+    }
+
+    /// Compresses and encrypts a chunk, it is sealed in write-only mode
+    fn encrypt_chunk(
+        &self,
+        chunk_id: &Hash256,
+        data: &[u8],
+        compression: Compression,
+    ) -> Result<Vec<u8>> {
+        let chunk = Chunk {
+            data: data.to_vec(),
+        };
+        let aad = associated_data(ObjectType::Chunk, chunk_id);
+        match &self.sealing {
+            Some(sealing) => chunk.compress_and_seal_with(compression, &sealing.public_key, &aad),
+            None => chunk.compress_and_encrypt_with(compression, &self.keys.chunk_enc_key, &aad),
+        }
+    }
+
+    /// Stores an encrypted chunk in the current pack and returns its location
+    ///
+    /// A full pack is finished, the next chunk starts a new one. With a maximum pack size of `0`
+    /// the chunk is written to a file of its own.
+    fn store_chunk(&mut self, chunk_id: &Hash256, encrypted_chunk: &[u8]) -> Result<ChunkLocation> {
+        if self.manifest.max_pack_size == 0 {
+            let file_name = self.chunk_db.new_file_name();
+            self.storage.put(&file_name, encrypted_chunk)?;
+            return Ok(ChunkLocation::file(file_name));
+        }
+
+        if self.pack_writer.is_none() {
+            let file_name = self.chunk_db.new_file_name();
+            self.pack_writer = Some(PackWriter::new(file_name));
+        }
+        let pack = self
+            .pack_writer
+            .as_mut()
+            .expect("this can not fail because the pack was just created");
+        let location = pack.append(chunk_id, encrypted_chunk);
+
+        if pack.len() >= self.manifest.max_pack_size {
+            self.finish_pack()?;
+        }
+
+        Ok(location)
+    }
+
+    /// Appends the index to the current pack, new chunks are stored in a new pack afterwards
+    fn finish_pack(&mut self) -> Result<()> {
+        if let Some(pack) = self.pack_writer.take() {
+            let aad = associated_data(ObjectType::PackIndex, &PackIndex::pack_id(pack.file_name()));
+            let encrypted_index = match &self.sealing {
+                Some(sealing) => pack.index().seal_with_aad(&sealing.public_key, &aad)?,
+                None => pack
+                    .index()
+                    .encrypt_with_aad(&self.keys.chunk_enc_key, &aad)?,
+            };
+            pack.finish(self.storage.as_ref(), &encrypted_index)?;
+        }
+        Ok(())
+    }
+
+    /// Reads and decrypts the index of a finished pack from the storage backend
+    fn read_pack_index(&self, file_name: &Path) -> Result<PackIndex> {
+        let data = PackIndex::read_encrypted(self.storage.as_ref(), file_name)?;
+        let aad = associated_data(ObjectType::PackIndex, &PackIndex::pack_id(file_name));
+        match &self.sealing {
+            Some(sealing) => PackIndex::open_with_aad(&data, sealing.secret_key()?, &aad),
+            None => PackIndex::decrypt_with_aad(&data, &self.keys.chunk_enc_key, &aad),
+        }
+    }
+
+    /// Reads and decrypts a chunk from the storage backend
+    fn read_chunk(&self, chunk_id: &Hash256) -> Result<Chunk> {
+        let location = self
+            .chunk_db
+            .get_location(chunk_id)?
+            .ok_or(BackrubError::ChunkDidNotExist(*chunk_id))?;
+        let data = self.read_stored_chunk(&location)?;
+        self.decrypt_chunk(chunk_id, &data)
+    }
+
+    /// Reads an encrypted chunk, chunks of the current pack are not stored yet
+    fn read_stored_chunk(&self, location: &ChunkLocation) -> Result<Vec<u8>> {
+        if let (Some(pack), Some(range)) = (&self.pack_writer, location.range) {
+            if pack.file_name() == location.file_name {
+                return pack
+                    .read(range)
+                    .map(|data| data.to_vec())
+                    .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+        location.read(self.storage.as_ref())
+    }
+
+    /// Decrypts the contents of a chunk file, which are sealed in write-only mode
+    fn decrypt_chunk(&self, chunk_id: &Hash256, data: &[u8]) -> Result<Chunk> {
+        let aad = associated_data(ObjectType::Chunk, chunk_id);
+        match &self.sealing {
+            Some(sealing) => Chunk::open_and_uncompress_with_aad(data, sealing.secret_key()?, &aad),
+            None => Chunk::decrypt_and_uncompress_with_aad(data, &self.keys.chunk_enc_key, &aad),
+        }
+    }
+
+    /// Binds the chunk files written by manifest format version 2 to their chunk ids
+    fn add_associated_data_to_chunk_files(&self) -> Result<()> {
+        for (chunk_id, (_, location)) in self.chunk_db.get_mappings()? {
+            // format version 2 had no packs
+            if location.is_packed() {
+                continue;
+            }
+            let data = match self.storage.get(&location.file_name) {
+                Ok(data) => data,
+                // missing chunk files are reported by the repository check
+                Err(e) if e.io_error_kind() == Some(std::io::ErrorKind::NotFound) => continue,
+                Err(e) => return Err(e),
+            };
+
+            let aad = associated_data(ObjectType::Chunk, &chunk_id);
+            match add_associated_data(&data, &self.keys.chunk_enc_key, &aad) {
+                Ok(Some(data)) => self.storage.put(&location.file_name, &data)?,
+                Ok(None) => {}
+                // corrupt chunk files are reported by the repository check
+                Err(Error::CryptoError(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gets an inode that is expected to exist
+    fn get_existing_inode(&self, key: &Hash256) -> Result<Inode> {
+        match self.inode_db.get_inode(key)? {
+            Some(inode) => Ok(inode),
+            None => Err(BackrubError::InodeDidNotExist(*key).into()),
+        }
+    }
+
+    /// Restores `source_subpath` of the backup stored under `backup` to `target_dir`
+    ///
+    /// `source_subpath` is relative to the backup root, an empty path restores the whole backup.
+    /// If it refers to a directory its contents are restored into `target_dir`,
+    /// otherwise the entry is restored as `target_dir/<file name>`.
+    ///
+    /// Files whose content does not match [`File::file_hash`](structs::File) or whose chunks
+    /// can not be read are not written, they are listed in the returned [`RestoreReport`] instead.
+    pub fn restore(
+        &self,
+        backup: &Hash256,
+        source_subpath: &Path,
+        target_dir: &Path,
+        opts: &RestoreConf,
+    ) -> Result<RestoreReport> {
+        self.require_full_access()?;
+        // the chunks must not be removed while they are restored
+        let _lock = self.locks.acquire(LockKind::Shared)?;
+        let backup = self
+            .backup_db
+            .get(backup)?
+            .ok_or(BackrubError::BackupDidNotExist(*backup))?;
+        if !backup.finished {
+            return Err(BackrubError::BackupNotFinished(backup.id).into());
+        }
+
+        let source_subpath = source_subpath.strip_prefix("/").unwrap_or(source_subpath);
+        let inode = self.find_inode(&backup.root, source_subpath)?;
+
+        // everything below base is restored into target_dir
+        let base = match &inode {
+            Inode::Directory(_) => source_subpath,
+            _ => source_subpath.parent().unwrap_or_else(|| Path::new("")),
+        };
+
+        let mut report = RestoreReport::default();
+        fs::create_dir_all(target_dir)?;
+        self.restore_inode(inode, base, target_dir, opts, &mut report)?;
+
+        Ok(report)
+    }
+
+    /// Finds the inode stored under `relpath` by descending from the directory inode `root`
+    fn find_inode(&self, root: &Hash256, relpath: &Path) -> Result<Inode> {
+        let mut inode = self.get_existing_inode(root)?;
+
+        'descend: while inode.relpath() != relpath {
+            if let Inode::Directory(dir) = &inode {
+                for key in dir.contents.iter() {
+                    let child = self.get_existing_inode(key)?;
+                    if relpath.starts_with(child.relpath()) {
+                        inode = child;
+                        continue 'descend;
                     }
                 }
-                Err(e) => { todo!("report error: {:?}",e) }
             }
+            return Err(BackrubError::PathNotInBackup(relpath.to_path_buf()).into());
+        }
+
+        Ok(inode)
+    }
+
+    /// Recursively restores an inode, paths are made relative to `base` and placed below `target_dir`
+    fn restore_inode(
+        &self,
+        inode: Inode,
+        base: &Path,
+        target_dir: &Path,
+        opts: &RestoreConf,
+        report: &mut RestoreReport,
+    ) -> Result<()> {
+        let target = target_dir.join(
+            inode
+                .relpath()
+                .strip_prefix(base)
+                .expect("this can not fail because all restored inodes are below base"),
+        );
+
+        match inode {
+            Inode::Directory(dir) => {
+                fs::create_dir_all(&target)?;
+                for key in dir.contents.iter() {
+                    self.restore_inode(
+                        self.get_existing_inode(key)?,
+                        base,
+                        target_dir,
+                        opts,
+                        report,
+                    )?;
+                }
+                // restoring the contents changes the mtime, so metadata has to be applied last
+                restore_metadata(&target, &dir.metadata, opts)?;
+                report.directories += 1;
+            }
+            Inode::File(file) => {
+                if !opts.overwrite && target.symlink_metadata().is_ok() {
+                    return Err(BackrubError::RestoreTargetExists(target).into());
+                }
+                match self.restore_file(&file, &target)? {
+                    FileRestore::Restored => {
+                        restore_metadata(&target, &file.metadata, opts)?;
+                        report.files += 1;
+                    }
+                    FileRestore::HashMismatch => report.hash_mismatches.push(file.relpath),
+                    FileRestore::UnreadableChunk => report.unreadable_chunks.push(file.relpath),
+                }
+            }
+            Inode::Symlink(link) => {
+                if target.symlink_metadata().is_ok() {
+                    if !opts.overwrite {
+                        return Err(BackrubError::RestoreTargetExists(target).into());
+                    }
+                    fs::remove_file(&target)?;
+                }
+                std::os::unix::fs::symlink(&link.target, &target)?;
+                restore_symlink_metadata(&target, &link.metadata, opts)?;
+                report.symlinks += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reassembles a file from its chunks
+    ///
+    /// The data is written to a temporary sibling of `target` which is only moved into place
+    /// if all chunks could be read and the content matches the file hash.
+    fn restore_file(&self, file: &structs::File, target: &Path) -> Result<FileRestore> {
+        let mut tmp_name = std::ffi::OsString::from(".");
+        tmp_name.push(target.file_name().unwrap_or_default());
+        tmp_name.push(".backrub-restore");
+        let tmp_path = target.with_file_name(tmp_name);
+
+        let file_hash = match self.write_file_content(file, &tmp_path) {
+            Ok(Some(file_hash)) => file_hash,
+            Ok(None) => {
+                fs::remove_file(&tmp_path)?;
+                return Ok(FileRestore::UnreadableChunk);
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        };
+
+        if file_hash != file.file_hash {
+            fs::remove_file(&tmp_path)?;
+            return Ok(FileRestore::HashMismatch);
+        }
+
+        fs::rename(&tmp_path, target)?;
+        Ok(FileRestore::Restored)
+    }
+
+    /// Writes the chunks of `file` to `path` and returns the file hash of the written data
+    ///
+    /// Returns `None` if a chunk is missing or corrupt, errors are only those of writing `path`.
+    fn write_file_content(&self, file: &structs::File, path: &Path) -> Result<Option<Hash256>> {
+        let mut out = fs::File::create(path)?;
+        let mut hasher = blake3::Hasher::new_keyed(self.keys.inode_hash_key.as_array());
+
+        for chunk_id in file.chunk_ids.iter() {
+            let Ok(chunk) = self.read_chunk(chunk_id) else {
+                return Ok(None);
+            };
+            hasher.update(&chunk.data);
+            out.write_all(&chunk.data)?;
+        }
+        out.sync_all()?;
+
+        Ok(Some(Hash256::from(hasher.finalize().as_bytes())))
+    }
+}
+
+/// Outcome of restoring the content of a single file
+enum FileRestore {
+    Restored,
+    HashMismatch,
+    UnreadableChunk,
+}
+
+/// Derives the legacy key slot verification key and the key encryption keys from a secret
+///
+/// Passwords are stretched with Argon2, random keys only need a fast key derivation.
+pub(crate) fn derive_keys(
+    secret: &Secret,
+    salt: &[u8; SALT_SIZE],
+    argon2_conf: Option<&Argon2Conf>,
+) -> Result<(Key256, KeyEncryptionKeys)> {
+    let crypto_root = Zeroizing::new(match (secret, argon2_conf) {
+        (Secret::Password(password), Some(argon2_conf)) => {
+            argon2::hash_raw(password.as_bytes(), salt, &argon2_conf.as_argon2config()?)?
+        }
+        (Secret::Key(key), None) => {
+            let mut crypto_root = vec![0u8; TOTAL_KEY_SIZE];
+            let mut hasher = blake3::Hasher::new_derive_key("backrub key file slot v1");
+            hasher.update(salt);
+            hasher.update(key.as_array());
+            hasher.finalize_xof().fill(&mut crypto_root);
+            crypto_root
+        }
+        _ => return Err(BackrubError::WrongPassword.into()),
+    });
+
+    // Derive legacy key slot verification key
+    let slot_key = Key256::try_from(&crypto_root[..KEY_SIZE])?;
+
+    // Derive keys, borrowing from `crypto_root` so no copy outlives it
+    let key_encryption_keys = <&[u8; CRYPTO_KEYS_SIZE]>::try_from(&crypto_root[KEY_SIZE..])?;
+    let key_encryption_keys = KeyEncryptionKeys::from(key_encryption_keys);
+
+    Ok((slot_key, key_encryption_keys))
+}
+
+/// Derives the signature key and the key encryption keys of a manifest without key slots
+///
+/// Its Argon2 output only covers the signature key and the four data keys of [`EncCryptoKeysV0`].
+fn derive_keys_v0(
+    password: &str,
+    salt: &[u8; SALT_SIZE],
+    argon2_conf: &Argon2Conf,
+) -> Result<(Key256, KeyEncryptionKeys)> {
+    let mut config = argon2_conf.as_argon2config()?;
+    config.hash_length = (KEY_SIZE + CRYPTO_KEYS_SIZE_V0) as u32;
+    let crypto_root = Zeroizing::new(argon2::hash_raw(password.as_bytes(), salt, &config)?);
+
+    let sig_key = Key256::try_from(&crypto_root[..KEY_SIZE])?;
+    // the keys that did not exist yet are not wrapped, their key encryption keys stay zero
+    let mut key_encryption_keys = Zeroizing::new([0u8; CRYPTO_KEYS_SIZE]);
+    key_encryption_keys[..CRYPTO_KEYS_SIZE_V0].copy_from_slice(&crypto_root[KEY_SIZE..]);
+
+    Ok((sig_key, KeyEncryptionKeys::from(&*key_encryption_keys)))
+}
+
+/// Verifier of a key slot in manifest format version 1
+pub(crate) fn key_slot_verifier_v1(slot_key: &Key256, salt: &[u8; SALT_SIZE]) -> Hash256 {
+    Hash256::from(*blake3::keyed_hash(slot_key.as_array(), salt).as_bytes())
+}
+
+/// Wraps `keys` into a new key slot for `secret`
+///
+/// `argon2_conf` is only used if `secret` is a password.
+fn new_key_slot(
+    keys: &CryptoKeys,
+    label: &str,
+    secret: &Secret,
+    argon2_conf: Argon2Conf,
+) -> Result<KeySlot> {
+    let mut salt = [0u8; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+
+    let argon2_conf = secret.is_password().then_some(argon2_conf);
+    let (_, key_encryption_keys) = derive_keys(secret, &salt, argon2_conf.as_ref())?;
+
+    Ok(KeySlot {
+        label: label.to_string(),
+        salt,
+        argon2_conf,
+        keys: keys.encrypt(&key_encryption_keys.wrapping_key())?,
+    })
+}
+
+/// Tries the key slots in order and returns the index of the first one `secret` unlocks
+///
+/// Password slots are skipped for random keys and the other way round.
+fn unlock_key_slots(key_slots: &[KeySlot], secret: &Secret) -> Result<(usize, CryptoKeys)> {
+    for (index, slot) in key_slots.iter().enumerate() {
+        if slot.argon2_conf.is_some() != secret.is_password() {
+            continue;
+        }
+        let (_, key_encryption_keys) = derive_keys(secret, &slot.salt, slot.argon2_conf.as_ref())?;
+        match CryptoKeys::decrypt(&slot.keys, &key_encryption_keys.wrapping_key()) {
+            Ok(keys) => return Ok((index, keys)),
+            Err(Error::CryptoError(_)) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(BackrubError::WrongPassword.into())
+}
+
+/// Converts a manifest of format version 1 with XOR wrapped keys to the current format
+///
+/// The key encryption keys of every slot can be recovered from the XOR wrapped keys, so all
+/// slots are rewrapped and not only the one unlocked by `secret`.
+/// Format version 1 only had password slots.
+fn migrate_manifest_v1(
+    manifest: SignedManifestV1,
+    secret: &Secret,
+) -> Result<(Manifest, CryptoKeys)> {
+    if !secret.is_password() {
+        return Err(BackrubError::WrongPassword.into());
+    }
+
+    let mut keys = None;
+    for slot in manifest.manifest.key_slots.iter() {
+        let (slot_key, key_encryption_keys) =
+            derive_keys(secret, &slot.salt, Some(&slot.argon2_conf))?;
+        // comparing blake3::Hash values is constant-time
+        let verifier = key_slot_verifier_v1(&slot_key, &slot.salt);
+        if blake3::Hash::from(verifier.0) == blake3::Hash::from(slot.verifier.0) {
+            keys = Some(slot.keys.xor_unwrap(&key_encryption_keys));
+            break;
+        }
+    }
+    let keys = keys.ok_or(BackrubError::WrongPassword)?;
+
+    let manifest = manifest.verify(&keys.manifest_sig_key)?;
+
+    let key_slots = manifest
+        .key_slots
+        .into_iter()
+        .map(|slot| {
+            let key_encryption_keys = slot.keys.key_encryption_keys(&keys);
+            Ok(KeySlot {
+                label: slot.label,
+                salt: slot.salt,
+                argon2_conf: Some(slot.argon2_conf),
+                keys: keys.encrypt(&key_encryption_keys.wrapping_key())?,
+            })
+        })
+        .collect::<Result<Vec<KeySlot>>>()?;
+
+    let manifest = Manifest {
+        format_version: 2,
+        chunk_root_dir: manifest.chunk_root_dir,
+        db_path: manifest.db_path,
+        version: manifest.version,
+        chunker_conf: manifest.chunker_conf,
+        key_slots,
+        chunk_db_state: manifest.chunk_db_state,
+        generation: 0,
+        previous_manifest_hash: Hash256::default(),
+        key_pair: None,
+        compression: Compression::default(),
+        max_pack_size: DEFAULT_MAX_PACK_SIZE,
+        storage: StorageConf::Local,
+    };
+
+    Ok((manifest, keys))
+}
+
+/// Migrates a manifest without key slots, its password becomes the `default` key slot
+fn migrate_manifest_v0(
+    manifest: SignedManifestV0,
+    secret: &Secret,
+) -> Result<(Manifest, CryptoKeys)> {
+    let Secret::Password(password) = secret else {
+        return Err(BackrubError::WrongPassword.into());
+    };
+    let (sig_key, key_encryption_keys) = derive_keys_v0(
+        password,
+        &manifest.manifest.salt,
+        &manifest.manifest.argon2_conf,
+    )?;
+    // the signature key is derived from the password, so a wrong one fails the verification
+    let manifest = manifest
+        .verify(&sig_key)
+        .map_err(|_| BackrubError::WrongPassword)?;
+    let keys = manifest.keys.xor_unwrap(&key_encryption_keys);
+    let key_slot = new_key_slot(&keys, "default", secret, manifest.argon2_conf)?;
+
+    let manifest = Manifest {
+        format_version: 2,
+        chunk_root_dir: manifest.chunk_root_dir,
+        db_path: manifest.db_path,
+        version: manifest.version,
+        chunker_conf: manifest.chunker_conf,
+        key_slots: vec![key_slot],
+        chunk_db_state: manifest.chunk_db_state,
+        generation: 0,
+        previous_manifest_hash: Hash256::default(),
+        key_pair: None,
+        compression: Compression::default(),
+        max_pack_size: DEFAULT_MAX_PACK_SIZE,
+        storage: StorageConf::Local,
+    };
+
+    Ok((manifest, keys))
+}
+
+/// Reads the manifest at `manifest_path` and verifies its signature with the keys `secret` unlocks
+fn read_manifest(manifest_path: &Path, secret: &Secret) -> Result<(Manifest, CryptoKeys)> {
+    let manifest = fs::read_to_string(manifest_path)?;
+
+    let manifest: serde_json::Value = serde_json::from_str(&manifest)?;
+    let format_version = match manifest["manifest"]["format_version"].as_u64() {
+        Some(version) => version,
+        // the first manifests had a single password instead of key slots
+        None if manifest["manifest"]["key_slots"].is_null() => 0,
+        None => 1,
+    };
+
+    match format_version {
+        0 => migrate_manifest_v0(serde_json::from_value(manifest)?, secret),
+        1 => migrate_manifest_v1(serde_json::from_value(manifest)?, secret),
+        version if (2..=MANIFEST_FORMAT_VERSION as u64).contains(&version) => {
+            let manifest: SignedManifest = serde_json::from_value(manifest)?;
+
+            let (mut manifest, keys) = match secret {
+                Secret::WriteOnly(write_only_keys) => (
+                    manifest.verify_write_only(write_only_keys.manifest_verify_key())?,
+                    write_only_keys.crypto_keys(),
+                ),
+                _ => {
+                    let keys = unlock_key_slots(&manifest.manifest.key_slots, secret)?.1;
+                    (manifest.verify(&keys.manifest_sig_key)?, keys)
+                }
+            };
+            // older manifests do not sign the compression, so it can not be trusted
+            if manifest.format_version < COMPRESSION_FORMAT_VERSION {
+                manifest.compression = Compression::default();
+            }
+            // chunks of older repositories have files of their own, new ones are packed
+            if manifest.format_version < PACK_FORMAT_VERSION {
+                manifest.max_pack_size = DEFAULT_MAX_PACK_SIZE;
+            }
+            if manifest.format_version < STORAGE_FORMAT_VERSION {
+                manifest.storage = StorageConf::Local;
+            }
+
+            Ok((manifest, keys))
         }
+        version => Err(BackrubError::UnsupportedManifestVersion(version).into()),
+    }
+}
 
-        for dir in dirs{
-            match self.backup_dir(dir.as_path(), conf) {
-                Ok(mut inodes) => { result.append(&mut inodes) }
-                Err(e) => { todo!("report error: {:?}",e) }
+/// Opens the files cache of a client
+///
+/// Write-only clients know the inode keys, so the cache of full clients is encrypted with keys
+/// derived from the backup key instead. Write-only clients keep a cache of their own.
+fn open_files_cache(db: &sled::Db, keys: &CryptoKeys, write_only: bool) -> Result<FilesCache> {
+    let (tree, key) = match write_only {
+        true => (
+            db.open_tree(b"write_only_files_cache")?,
+            &keys.inode_enc_key,
+        ),
+        false => (db.open_tree(b"files_cache")?, &keys.backup_enc_key),
+    };
+    let derive = |context| Key256::from(blake3::derive_key(context, key.as_array()));
+    FilesCache::new(
+        tree,
+        derive(FILES_CACHE_ENC_CONTEXT),
+        derive(FILES_CACHE_HASH_CONTEXT),
+    )
+}
+
+/// Opens the database, waits a moment if a dropped [`BackupManager`] still holds it
+///
+/// sled releases the lock on the database files in a background thread.
+fn open_database(path: &Path) -> Result<sled::Db> {
+    let mut attempts = 0;
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(e))
+                if attempts < 20 && e.to_string().contains("could not acquire lock") =>
+            {
+                attempts += 1;
+                std::thread::sleep(std::time::Duration::from_millis(100));
             }
+            db => return Ok(db?),
+        }
+    }
+}
+
+/// Path of the copy of the previous manifest, `backrub.manifest.bak` for `backrub.manifest`
+pub fn manifest_backup_path(manifest_path: &Path) -> PathBuf {
+    let mut name = manifest_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".bak");
+    manifest_path.with_file_name(name)
+}
+
+/// Re-encrypts a ciphertext without associated data so it is bound to `aad`
+///
+/// Returns `None` if `data` is already bound to `aad`, so an interrupted migration can be resumed.
+fn add_associated_data(data: &[u8], key: &Key256, aad: &[u8]) -> Result<Option<Vec<u8>>> {
+    if Vec::<u8>::decrypt_with_aad(data, key, aad).is_ok() {
+        return Ok(None);
+    }
+    let plaintext = Vec::<u8>::decrypt(data, key)?;
+    Ok(Some(plaintext.encrypt_with_aad(key, aad)?))
+}
+
+/// Binds all entries of a sled tree to their keys
+fn add_associated_data_to_tree(
+    tree: &sled::Tree,
+    key: &Key256,
+    object_type: ObjectType,
+) -> Result<()> {
+    for data in tree.iter() {
+        let (id, encrypted_data) = data?;
+        let id = Hash256::try_from(id.as_ref())?;
+        if let Some(encrypted_data) =
+            add_associated_data(&encrypted_data, key, &associated_data(object_type, &id))?
+        {
+            tree.insert(id, encrypted_data)?;
         }
-        todo!()
     }
+
+    Ok(())
+}
+
+/// Applies ownership, permissions and timestamps to a restored file or directory
+fn restore_metadata(path: &Path, metadata: &structs::Metadata, opts: &RestoreConf) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // chown may clear the setuid and setgid bits, so it has to happen before chmod
+    if opts.restore_ownership {
+        std::os::unix::fs::chown(path, Some(metadata.uid), Some(metadata.gid))?;
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(metadata.mode & 0o7777))?;
+    filetime::set_file_times(
+        path,
+        filetime::FileTime::from_unix_time(metadata.atime, metadata.atime_ns as u32),
+        filetime::FileTime::from_unix_time(metadata.mtime, metadata.mtime_ns as u32),
+    )?;
+
+    Ok(())
+}
+
+/// Applies ownership and timestamps to a restored symlink without following it
+fn restore_symlink_metadata(
+    path: &Path,
+    metadata: &structs::Metadata,
+    opts: &RestoreConf,
+) -> Result<()> {
+    if opts.restore_ownership {
+        std::os::unix::fs::lchown(path, Some(metadata.uid), Some(metadata.gid))?;
+    }
+    filetime::set_symlink_file_times(
+        path,
+        filetime::FileTime::from_unix_time(metadata.atime, metadata.atime_ns as u32),
+        filetime::FileTime::from_unix_time(metadata.mtime, metadata.mtime_ns as u32),
+    )?;
+
+    Ok(())
 }
 
 use std::sync::Arc;
@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use super::error::*;
+use super::storage::*;
+use super::structs::*;
+use super::traits::*;
+
+/// Default upper bound of the size of a pack file
+pub const DEFAULT_MAX_PACK_SIZE: u64 = 64 * 1024 * 1024;
+/// Size of the little endian length of the encrypted index at the end of a pack
+const PACK_TRAILER_SIZE: u64 = 8;
+
+/// Position of a chunk in a pack file
+#[derive(Clone, Copy, Hash, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PackRange {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Where a chunk is stored in the [`StorageBackend`]
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChunkLocation {
+    /// Pack file of the chunk or, for chunks that are not packed, the file of its own
+    pub file_name: PathBuf,
+    /// Position of the chunk in the pack, `None` if the chunk is the whole file
+    pub range: Option<PackRange>,
+}
+
+impl ChunkLocation {
+    /// Location of a chunk that is stored in a file of its own
+    pub fn file(file_name: PathBuf) -> Self {
+        ChunkLocation {
+            file_name,
+            range: None,
+        }
+    }
+
+    pub fn is_packed(&self) -> bool {
+        self.range.is_some()
+    }
+
+    /// Reads the encrypted chunk from `storage`
+    pub fn read(&self, storage: &dyn StorageBackend) -> Result<Vec<u8>> {
+        match self.range {
+            None => storage.get(&self.file_name),
+            Some(range) => storage.get_range(&self.file_name, range.offset, range.length),
+        }
+    }
+}
+
+/// Index at the end of a pack file, lists its chunks in the order they were appended
+///
+/// The chunk database is the authority on where chunks are stored, the index describes a pack
+/// without it. It is encrypted with associated data bound to the name of the pack.
+#[derive(Clone, Hash, Default, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PackIndex {
+    pub chunks: Vec<(Hash256, PackRange)>,
+}
+
+impl Encrypt for PackIndex {}
+
+impl PackIndex {
+    /// Id the index of the pack `file_name` is bound to by its associated data
+    pub fn pack_id(file_name: &Path) -> Hash256 {
+        Hash256::from(blake3::hash(file_name.as_os_str().as_bytes()).as_bytes())
+    }
+
+    /// Reads the encrypted index from the end of the finished pack `file_name`
+    pub fn read_encrypted(storage: &dyn StorageBackend, file_name: &Path) -> Result<Vec<u8>> {
+        let invalid = || BackrubError::InvalidPackFile(file_name.to_path_buf());
+
+        let size = storage
+            .size(file_name)?
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+        if size < PACK_TRAILER_SIZE {
+            return Err(invalid().into());
+        }
+
+        let trailer = storage.get_range(file_name, size - PACK_TRAILER_SIZE, PACK_TRAILER_SIZE)?;
+        let index_length = u64::from_le_bytes(
+            trailer
+                .try_into()
+                .expect("this can not fail because the trailer was read in full"),
+        );
+        if index_length > size - PACK_TRAILER_SIZE {
+            return Err(invalid().into());
+        }
+
+        storage.get_range(
+            file_name,
+            size - PACK_TRAILER_SIZE - index_length,
+            index_length,
+        )
+    }
+
+    /// Bytes at the end of a finished pack that do not belong to chunks, `0` if it has no index
+    pub fn stored_size(storage: &dyn StorageBackend, file_name: &Path) -> u64 {
+        match Self::read_encrypted(storage, file_name) {
+            Ok(data) => data.len() as u64 + PACK_TRAILER_SIZE,
+            Err(_) => 0,
+        }
+    }
+}
+
+/// Pack file that encrypted chunks are appended to until it is full
+///
+/// The pack is collected in memory and stored by [`PackWriter::finish`], until then its chunks
+/// are read with [`PackWriter::read`].
+pub(crate) struct PackWriter {
+    file_name: PathBuf,
+    data: Vec<u8>,
+    index: PackIndex,
+}
+
+/// The collected chunks are left out
+impl fmt::Debug for PackWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PackWriter")
+            .field("file_name", &self.file_name)
+            .field("length", &self.data.len())
+            .field("chunks", &self.index.chunks.len())
+            .finish()
+    }
+}
+
+impl PackWriter {
+    /// Starts the pack `file_name`, it replaces an unused object of that name when it is stored
+    pub fn new(file_name: PathBuf) -> Self {
+        PackWriter {
+            file_name,
+            data: Vec::new(),
+            index: PackIndex::default(),
+        }
+    }
+
+    pub fn file_name(&self) -> &Path {
+        &self.file_name
+    }
+
+    pub fn index(&self) -> &PackIndex {
+        &self.index
+    }
+
+    /// Number of bytes of chunks in the pack
+    pub fn len(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// Appends an encrypted chunk and returns its location
+    pub fn append(&mut self, chunk_id: &Hash256, data: &[u8]) -> ChunkLocation {
+        let range = PackRange {
+            offset: self.len(),
+            length: data.len() as u64,
+        };
+        self.data.extend_from_slice(data);
+        self.index.chunks.push((*chunk_id, range));
+
+        ChunkLocation {
+            file_name: self.file_name.clone(),
+            range: Some(range),
+        }
+    }
+
+    /// Returns a chunk of the pack, `None` if it ends before `range`
+    pub fn read(&self, range: PackRange) -> Option<&[u8]> {
+        let end = range.offset.checked_add(range.length)?;
+        self.data.get(range.offset as usize..end as usize)
+    }
+
+    /// Appends the encrypted index and the trailer and stores the pack
+    pub fn finish(mut self, storage: &dyn StorageBackend, encrypted_index: &[u8]) -> Result<()> {
+        self.data.extend_from_slice(encrypted_index);
+        self.data
+            .extend_from_slice(&(encrypted_index.len() as u64).to_le_bytes());
+        storage.put(&self.file_name, &self.data)
+    }
+}
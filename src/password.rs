@@ -0,0 +1,278 @@
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use std::{
+    env, fmt, fs,
+    io::prelude::*,
+    os::unix::{
+        fs::OpenOptionsExt,
+        io::{BorrowedFd, RawFd},
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+};
+use zeroize::{Zeroize, Zeroizing};
+
+use super::error::*;
+use super::structs::*;
+
+/// Secret that unlocks a key slot
+///
+/// Passwords are wiped from memory when the secret is dropped.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Secret {
+    /// Password that is stretched with Argon2
+    Password(String),
+    /// Random key with full entropy, used without Argon2 stretching
+    Key(Key256),
+    /// Keys of a write-only client, see [`WriteOnlyKeys`]
+    WriteOnly(Box<WriteOnlyKeys>),
+}
+
+impl Secret {
+    pub fn is_password(&self) -> bool {
+        matches!(self, Secret::Password(_))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Secret::Password(_) => write!(f, "Password(<redacted>)"),
+            Secret::Key(_) => write!(f, "Key(<redacted>)"),
+            Secret::WriteOnly(_) => write!(f, "WriteOnly(<redacted>)"),
+        }
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        if let Secret::Password(password) = self {
+            password.zeroize();
+        }
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(password: &str) -> Self {
+        Secret::Password(password.to_string())
+    }
+}
+
+impl From<String> for Secret {
+    fn from(password: String) -> Self {
+        Secret::Password(password)
+    }
+}
+
+impl From<Key256> for Secret {
+    fn from(key: Key256) -> Self {
+        Secret::Key(key)
+    }
+}
+
+impl From<WriteOnlyKeys> for Secret {
+    fn from(keys: WriteOnlyKeys) -> Self {
+        Secret::WriteOnly(Box::new(keys))
+    }
+}
+
+impl From<&Secret> for Secret {
+    fn from(secret: &Secret) -> Self {
+        secret.clone()
+    }
+}
+
+/// Where the secret that unlocks a repository is read from
+///
+/// All sources except [`PasswordSource::KeyFile`] and [`PasswordSource::WriteOnlyKeyFile`] yield a
+/// password, a single trailing newline is
+/// removed from it.
+/// As a string a source is given as `file:<path>`, `fd:<number>`, `env:<variable>`,
+/// `command:<shell command>`, `keyfile:<path>` or `writeonly:<path>`,
+/// see [`PasswordSource::from_str`].
+///
+/// The crate has no command line interface that selects a source. Programs that take one as an
+/// argument parse it with [`PasswordSource::from_str`] and hand the [`Secret`] returned by
+/// [`PasswordSource::read`] to the [`BackupManager`](crate::manager::BackupManager).
+#[derive(Clone, PartialEq, Eq)]
+pub enum PasswordSource {
+    /// The password itself
+    Password(String),
+    /// Contents of a file
+    File(PathBuf),
+    /// Everything readable from an inherited file descriptor
+    ///
+    /// The descriptor stays open, it belongs to whoever handed it over.
+    FileDescriptor(RawFd),
+    /// Value of an environment variable
+    Env(String),
+    /// Standard output of a command run by `sh -c`
+    Command(String),
+    /// Random key written by [`PasswordSource::generate_key_file`]
+    KeyFile(PathBuf),
+    /// Write-only keys written by [`WriteOnlyKeys::write_to_file`]
+    WriteOnlyKeyFile(PathBuf),
+}
+
+impl PasswordSource {
+    /// Reads the secret from its source
+    pub fn read(&self) -> Result<Secret> {
+        // intermediate copies of the password are wiped as soon as they are dropped
+        let password = Zeroizing::new(match self {
+            PasswordSource::Password(password) => password.clone(),
+            PasswordSource::File(path) => fs::read_to_string(path)?,
+            PasswordSource::FileDescriptor(fd) => {
+                // Safety: the descriptor is only borrowed to duplicate it, reading from the
+                // duplicate leaves the original open
+                let fd = unsafe { BorrowedFd::borrow_raw(*fd) };
+                let mut file = fs::File::from(fd.try_clone_to_owned()?);
+                let mut password = Zeroizing::new(String::new());
+                file.read_to_string(&mut password)?;
+                std::mem::take(&mut *password)
+            }
+            PasswordSource::Env(name) => {
+                env::var(name).map_err(|_| BackrubError::EnvVarNotSet(name.clone()))?
+            }
+            PasswordSource::Command(command) => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(Stdio::inherit())
+                    .stderr(Stdio::inherit())
+                    .output()?;
+                if !output.status.success() {
+                    return Err(BackrubError::PasswordCommandFailed(output.status.code()).into());
+                }
+                // invalid UTF-8 is reported like it is for files
+                String::from_utf8(output.stdout).map_err(|e| {
+                    let error = e.utf8_error();
+                    e.into_bytes().zeroize();
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+                })?
+            }
+            PasswordSource::KeyFile(path) => {
+                let contents = Zeroizing::new(fs::read_to_string(path)?);
+                let key = parse_key(contents.trim())
+                    .ok_or_else(|| BackrubError::InvalidKeyFile(path.clone()))?;
+                return Ok(Secret::Key(key));
+            }
+            PasswordSource::WriteOnlyKeyFile(path) => {
+                let contents = Zeroizing::new(fs::read_to_string(path)?);
+                let keys: WriteOnlyKeys = serde_json::from_str(&contents)
+                    .map_err(|_| BackrubError::InvalidKeyFile(path.clone()))?;
+                return Ok(keys.into());
+            }
+        });
+
+        let password = password
+            .strip_suffix('\n')
+            .map(|password| password.strip_suffix('\r').unwrap_or(password))
+            .unwrap_or(&password);
+        if password.is_empty() {
+            return Err(BackrubError::EmptyPassword.into());
+        }
+
+        Ok(Secret::Password(password.to_string()))
+    }
+
+    /// Writes a new random key to `path`, readable only by the owner
+    ///
+    /// Fails if the file already exists.
+    pub fn generate_key_file(path: &Path) -> Result<()> {
+        let mut key = Key256([0u8; KEY_SIZE]);
+        OsRng.fill_bytes(&mut key.0);
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        writeln!(file, "{}", *format_key(&key))?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+}
+
+impl FromStr for PasswordSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || BackrubError::InvalidPasswordSource(s.to_string());
+        let (kind, value) = s.split_once(':').ok_or_else(invalid)?;
+        match kind {
+            "file" => Ok(PasswordSource::File(PathBuf::from(value))),
+            "fd" => Ok(PasswordSource::FileDescriptor(
+                value.parse().map_err(|_| invalid())?,
+            )),
+            "env" => Ok(PasswordSource::Env(value.to_string())),
+            "command" => Ok(PasswordSource::Command(value.to_string())),
+            "keyfile" => Ok(PasswordSource::KeyFile(PathBuf::from(value))),
+            "writeonly" => Ok(PasswordSource::WriteOnlyKeyFile(PathBuf::from(value))),
+            _ => Err(invalid().into()),
+        }
+    }
+}
+
+impl Drop for PasswordSource {
+    fn drop(&mut self) {
+        if let PasswordSource::Password(password) = self {
+            password.zeroize();
+        }
+    }
+}
+
+impl fmt::Debug for PasswordSource {
+    /// Like [`fmt::Display`], never shows a password
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PasswordSource::Password(_) => write!(f, "Password(<redacted>)"),
+            PasswordSource::File(path) => f.debug_tuple("File").field(path).finish(),
+            PasswordSource::FileDescriptor(fd) => {
+                f.debug_tuple("FileDescriptor").field(fd).finish()
+            }
+            PasswordSource::Env(name) => f.debug_tuple("Env").field(name).finish(),
+            PasswordSource::Command(command) => f.debug_tuple("Command").field(command).finish(),
+            PasswordSource::KeyFile(path) => f.debug_tuple("KeyFile").field(path).finish(),
+            PasswordSource::WriteOnlyKeyFile(path) => {
+                f.debug_tuple("WriteOnlyKeyFile").field(path).finish()
+            }
+        }
+    }
+}
+
+impl fmt::Display for PasswordSource {
+    /// Formats the source like [`PasswordSource::from_str`] parses it, but never shows a password
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PasswordSource::Password(_) => write!(f, "password:***"),
+            PasswordSource::File(path) => write!(f, "file:{}", path.display()),
+            PasswordSource::FileDescriptor(fd) => write!(f, "fd:{}", fd),
+            PasswordSource::Env(name) => write!(f, "env:{}", name),
+            PasswordSource::Command(command) => write!(f, "command:{}", command),
+            PasswordSource::KeyFile(path) => write!(f, "keyfile:{}", path.display()),
+            PasswordSource::WriteOnlyKeyFile(path) => write!(f, "writeonly:{}", path.display()),
+        }
+    }
+}
+
+fn format_key(key: &Key256) -> Zeroizing<String> {
+    use std::fmt::Write as _;
+
+    let mut hex = Zeroizing::new(String::with_capacity(KEY_SIZE * 2));
+    for byte in key.as_array() {
+        write!(hex, "{byte:02x}").expect("writing to a String can not fail");
+    }
+    hex
+}
+
+fn parse_key(hex: &str) -> Option<Key256> {
+    if hex.len() != KEY_SIZE * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = Key256([0u8; KEY_SIZE]);
+    for (byte, pair) in key.0.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
@@ -0,0 +1,181 @@
+use std::{fmt::Write as _, str::FromStr};
+use zeroize::Zeroizing;
+
+use super::error::*;
+use super::structs::*;
+
+/// Version of the layout encoded by [`RecoveryKey::encode`]
+pub const RECOVERY_KEY_VERSION: u8 = 1;
+
+/// RFC 4648 base32 alphabet, it has no digits that look like letters
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+/// Bytes encoded per line, 24 characters in groups of 4
+const LINE_BYTES: usize = 15;
+const GROUP_CHARS: usize = 4;
+/// Each line ends with a group of checksum characters, 20 bits of a hash over the line
+const CHECKSUM_CHARS: usize = 4;
+const FLAG_SECRET_KEY: u8 = 1;
+
+/// Paper backup of the keys of a repository
+///
+/// Anyone who holds it can decrypt the whole repository, it must be kept as safe as the
+/// password. Together with the database and the chunk directory it restores a lost manifest,
+/// see [`BackupManager::recover`](crate::manager::BackupManager::recover).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecoveryKey {
+    pub(crate) keys: CryptoKeys,
+    /// Secret key of the [`RepositoryKeyPair`] of write-only repositories
+    pub(crate) repository_secret_key: Option<Key256>,
+}
+
+impl RecoveryKey {
+    pub fn new(keys: CryptoKeys, repository_secret_key: Option<Key256>) -> Self {
+        RecoveryKey {
+            keys,
+            repository_secret_key,
+        }
+    }
+
+    /// Encodes the key as lines of base32 groups that can be typed in again
+    ///
+    /// The last group of every line is a checksum, so typos are found line by line.
+    pub fn encode(&self) -> Zeroizing<String> {
+        // allocated once, so no copies of the keys are left behind by reallocation
+        let mut payload = Zeroizing::new(Vec::with_capacity(2 + CRYPTO_KEYS_SIZE + KEY_SIZE + 5));
+        payload.push(RECOVERY_KEY_VERSION);
+        match &self.repository_secret_key {
+            Some(secret_key) => {
+                payload.push(FLAG_SECRET_KEY);
+                payload.extend_from_slice(&*self.keys.to_bytes());
+                payload.extend_from_slice(secret_key.as_array());
+            }
+            None => {
+                payload.push(0);
+                payload.extend_from_slice(&*self.keys.to_bytes());
+            }
+        }
+        // base32 encodes 5 bytes at once
+        let padded_len = payload.len().div_ceil(5) * 5;
+        payload.resize(padded_len, 0);
+
+        // every byte takes less than 3 characters
+        let mut encoded = Zeroizing::new(String::with_capacity(payload.len() * 3));
+        for (index, line) in payload.chunks(LINE_BYTES).enumerate() {
+            let chars = encode_base32(line);
+            for group in chars.chunks(GROUP_CHARS) {
+                encoded.extend(group.iter().map(|&c| c as char));
+                encoded.push(' ');
+            }
+            writeln!(encoded, "{}", line_checksum(index, line))
+                .expect("writing to a String can not fail");
+        }
+        encoded
+    }
+}
+
+impl FromStr for RecoveryKey {
+    type Err = Error;
+
+    /// Parses a key encoded by [`RecoveryKey::encode`]
+    ///
+    /// Case, whitespace and dashes are ignored, `0`, `1` and `8` are read as `O`, `I` and `B`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: &str| BackrubError::InvalidRecoveryKey(reason.to_string());
+
+        let mut payload = Zeroizing::new(Vec::new());
+        let lines = s.lines().filter(|line| !line.trim().is_empty());
+        for (index, line) in lines.enumerate() {
+            let mut chars = Zeroizing::new(Vec::with_capacity(line.len()));
+            for c in line.chars() {
+                match c.to_ascii_uppercase() {
+                    c if c.is_whitespace() || c == '-' => {}
+                    '0' => chars.push(b'O'),
+                    '1' => chars.push(b'I'),
+                    '8' => chars.push(b'B'),
+                    c if c.is_ascii() && ALPHABET.contains(&(c as u8)) => chars.push(c as u8),
+                    _ => return Err(invalid("it contains characters that are not base32").into()),
+                }
+            }
+            if chars.len() <= CHECKSUM_CHARS || !(chars.len() - CHECKSUM_CHARS).is_multiple_of(8) {
+                return Err(BackrubError::RecoveryKeyTypo(index + 1).into());
+            }
+
+            let (data, checksum) = chars.split_at(chars.len() - CHECKSUM_CHARS);
+            let data = decode_base32(data);
+            if line_checksum(index, &data).as_bytes() != checksum {
+                return Err(BackrubError::RecoveryKeyTypo(index + 1).into());
+            }
+            payload.extend_from_slice(&data);
+        }
+
+        let (&version, payload) = payload
+            .split_first()
+            .ok_or_else(|| invalid("it is empty"))?;
+        if version != RECOVERY_KEY_VERSION {
+            return Err(invalid(&format!("version {} is not supported", version)).into());
+        }
+        let (&flags, payload) = payload
+            .split_first()
+            .ok_or_else(|| invalid("it is too short"))?;
+        if flags & !FLAG_SECRET_KEY != 0 {
+            return Err(invalid("it has unknown flags").into());
+        }
+
+        let key_size = CRYPTO_KEYS_SIZE + (flags & FLAG_SECRET_KEY) as usize * KEY_SIZE;
+        if payload.len() < key_size {
+            return Err(invalid("lines are missing").into());
+        }
+        let (key_bytes, padding) = payload.split_at(key_size);
+        if padding.len() >= 5 || padding.iter().any(|&byte| byte != 0) {
+            return Err(invalid("it has trailing data").into());
+        }
+
+        let (key_bytes, secret_key) = key_bytes.split_at(CRYPTO_KEYS_SIZE);
+        let keys = CryptoKeys::from(<&[u8; CRYPTO_KEYS_SIZE]>::try_from(key_bytes)?);
+        let repository_secret_key = match secret_key.is_empty() {
+            true => None,
+            false => Some(Key256::try_from(secret_key)?),
+        };
+
+        Ok(RecoveryKey::new(keys, repository_secret_key))
+    }
+}
+
+/// First 20 bits of a hash over the position and the bytes of a line, base32 encoded
+fn line_checksum(index: usize, line: &[u8]) -> String {
+    let mut hasher = blake3::Hasher::new_derive_key("backrub recovery key line v1");
+    hasher.update(&(index as u64).to_le_bytes());
+    hasher.update(line);
+    let hash = hasher.finalize();
+    let chars = encode_base32(&hash.as_bytes()[..5]);
+    String::from_utf8_lossy(&chars[..CHECKSUM_CHARS]).into_owned()
+}
+
+/// Encodes `data`, whose length must be a multiple of 5
+fn encode_base32(data: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mut chars = Zeroizing::new(Vec::with_capacity(data.len() / 5 * 8));
+    for block in data.chunks_exact(5) {
+        let bits = block
+            .iter()
+            .fold(0u64, |bits, &byte| (bits << 8) | u64::from(byte));
+        for i in (0..8).rev() {
+            chars.push(ALPHABET[((bits >> (i * 5)) & 31) as usize]);
+        }
+    }
+    chars
+}
+
+/// Decodes characters of the alphabet, their number must be a multiple of 8
+fn decode_base32(chars: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mut data = Zeroizing::new(Vec::with_capacity(chars.len() / 8 * 5));
+    for block in chars.chunks_exact(8) {
+        let bits = block.iter().fold(0u64, |bits, c| {
+            let value = ALPHABET.iter().position(|a| a == c).unwrap_or_default();
+            (bits << 5) | value as u64
+        });
+        for i in (0..5).rev() {
+            data.push((bits >> (i * 8)) as u8);
+        }
+    }
+    data
+}
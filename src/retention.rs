@@ -0,0 +1,200 @@
+use chrono::{DateTime, Datelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, path::PathBuf, time::Duration};
+
+use super::error::*;
+use super::structs::*;
+
+/// Policy that decides which backups are kept when pruning a repository
+///
+/// Backups are grouped by their source host and source path, every group is evaluated on its own.
+/// A backup is kept if at least one rule selects it, all other backups are forgotten.
+/// Calendar based rules are evaluated in UTC.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Keep the `n` most recent backups
+    pub keep_last: usize,
+    /// Keep the most recent backup of each of the last `n` days that have backups
+    pub keep_daily: usize,
+    /// Keep the most recent backup of each of the last `n` ISO weeks that have backups
+    pub keep_weekly: usize,
+    /// Keep the most recent backup of each of the last `n` months that have backups
+    pub keep_monthly: usize,
+    /// Keep the most recent backup of each of the last `n` years that have backups
+    pub keep_yearly: usize,
+    /// Keep all backups that are younger than this
+    pub keep_within: Option<Duration>,
+}
+
+/// The rule a backup was kept by
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum KeepReason {
+    Last,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+    Within,
+    /// Interrupted backups are never touched by a policy, they have to be deleted explicitly
+    Unfinished,
+}
+
+impl fmt::Display for KeepReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeepReason::Last => write!(f, "last"),
+            KeepReason::Daily => write!(f, "daily"),
+            KeepReason::Weekly => write!(f, "weekly"),
+            KeepReason::Monthly => write!(f, "monthly"),
+            KeepReason::Yearly => write!(f, "yearly"),
+            KeepReason::Within => write!(f, "within"),
+            KeepReason::Unfinished => write!(f, "unfinished"),
+        }
+    }
+}
+
+/// Result of evaluating a [`RetentionPolicy`]
+#[derive(Clone, Default, Debug, PartialEq, Eq, Hash)]
+pub struct RetentionReport {
+    /// Kept backups together with all rules that selected them
+    pub keep: Vec<(Backup, Vec<KeepReason>)>,
+    /// Backups that are forgotten
+    pub forget: Vec<Backup>,
+}
+
+impl fmt::Display for RetentionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (backup, reasons) in self.keep.iter() {
+            let reasons: Vec<String> = reasons.iter().map(|r| r.to_string()).collect();
+            writeln!(
+                f,
+                "keep   {} {} \"{}\": {}",
+                backup.id,
+                backup.timestamp,
+                backup.name,
+                reasons.join(", ")
+            )?;
+        }
+        for backup in self.forget.iter() {
+            writeln!(
+                f,
+                "forget {} {} \"{}\"",
+                backup.id, backup.timestamp, backup.name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// State of a calendar based rule while walking through the backups from newest to oldest
+struct Bucket {
+    reason: KeepReason,
+    remaining: usize,
+    last: Option<(i32, u32)>,
+}
+
+impl Bucket {
+    fn key(&self, time: &DateTime<Utc>) -> (i32, u32) {
+        match self.reason {
+            KeepReason::Daily => (time.year(), time.ordinal()),
+            KeepReason::Weekly => (time.iso_week().year(), time.iso_week().week()),
+            KeepReason::Monthly => (time.year(), time.month()),
+            _ => (time.year(), 0),
+        }
+    }
+
+    /// Returns `true` if the backup is the newest one in a not yet seen bucket
+    fn select(&mut self, time: &DateTime<Utc>) -> bool {
+        if self.remaining == 0 {
+            return false;
+        }
+        let key = self.key(time);
+        if self.last == Some(key) {
+            return false;
+        }
+        self.last = Some(key);
+        self.remaining -= 1;
+        true
+    }
+}
+
+impl RetentionPolicy {
+    /// Returns `true` if the policy would not keep any backup
+    pub fn is_empty(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+            && self.keep_yearly == 0
+            && self.keep_within.is_none()
+    }
+
+    /// Decides which of the given backups are kept, `keep_within` is relative to `now`
+    ///
+    /// Returns [`BackrubError::EmptyRetentionPolicy`] if the policy would forget every backup
+    pub fn evaluate(&self, backups: Vec<Backup>, now: DateTime<Utc>) -> Result<RetentionReport> {
+        if self.is_empty() {
+            return Err(BackrubError::EmptyRetentionPolicy.into());
+        }
+
+        let mut groups = BTreeMap::<(String, PathBuf), Vec<(DateTime<Utc>, Backup)>>::new();
+        for backup in backups {
+            let time = DateTime::parse_from_rfc3339(&backup.timestamp)?.with_timezone(&Utc);
+            groups
+                .entry((backup.source_host.clone(), backup.source_path.clone()))
+                .or_default()
+                .push((time, backup));
+        }
+
+        let mut report = RetentionReport::default();
+        for (_source, mut group) in groups {
+            // newest first
+            group.sort_by_key(|entry| std::cmp::Reverse(entry.0));
+
+            let mut last = self.keep_last;
+            let mut buckets = [
+                (KeepReason::Daily, self.keep_daily),
+                (KeepReason::Weekly, self.keep_weekly),
+                (KeepReason::Monthly, self.keep_monthly),
+                (KeepReason::Yearly, self.keep_yearly),
+            ]
+            .map(|(reason, remaining)| Bucket {
+                reason,
+                remaining,
+                last: None,
+            });
+
+            for (time, backup) in group {
+                if !backup.finished {
+                    report.keep.push((backup, vec![KeepReason::Unfinished]));
+                    continue;
+                }
+
+                let mut reasons = Vec::<KeepReason>::new();
+                if last > 0 {
+                    last -= 1;
+                    reasons.push(KeepReason::Last);
+                }
+                for bucket in buckets.iter_mut() {
+                    if bucket.select(&time) {
+                        reasons.push(bucket.reason);
+                    }
+                }
+                if let Some(within) = self.keep_within {
+                    // backups from the future are always kept
+                    if (now - time).to_std().ok().is_none_or(|age| age <= within) {
+                        reasons.push(KeepReason::Within);
+                    }
+                }
+
+                if reasons.is_empty() {
+                    report.forget.push(backup);
+                } else {
+                    report.keep.push((backup, reasons));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
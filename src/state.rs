@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use super::error::*;
+use super::structs::*;
+use super::utils::*;
+
+/// What a client last saw of a repository's manifest
+#[derive(Clone, Copy, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TrustedState {
+    pub generation: u64,
+    /// [`Manifest::digest`] of the manifest with that generation
+    pub manifest_digest: Hash256,
+    /// [`Manifest::previous_manifest_hash`] of that manifest, see [`TrustedStateStore::check_previous`]
+    #[serde(default)]
+    pub previous_manifest_hash: Hash256,
+}
+
+impl From<&Manifest> for TrustedState {
+    fn from(manifest: &Manifest) -> Self {
+        TrustedState {
+            generation: manifest.generation,
+            manifest_digest: manifest.digest(),
+            previous_manifest_hash: manifest.previous_manifest_hash,
+        }
+    }
+}
+
+/// Keeps one [`TrustedState`] per repository in a directory of the client
+///
+/// The directory must not be writable by whoever can modify the repository, otherwise it offers
+/// no protection against rolled back manifests.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TrustedStateStore {
+    dir: PathBuf,
+}
+
+impl TrustedStateStore {
+    pub fn new(dir: PathBuf) -> Self {
+        TrustedStateStore { dir }
+    }
+
+    /// `$BACKRUB_STATE_DIR`, `$XDG_STATE_HOME/backrub` or `$HOME/.local/state/backrub`
+    pub fn default_dir() -> Option<PathBuf> {
+        if let Some(dir) = env::var_os("BACKRUB_STATE_DIR") {
+            return Some(PathBuf::from(dir));
+        }
+        if let Some(dir) = env::var_os("XDG_STATE_HOME") {
+            return Some(Path::new(&dir).join("backrub"));
+        }
+        env::var_os("HOME").map(|home| Path::new(&home).join(".local/state/backrub"))
+    }
+
+    fn path(&self, repository_id: &Hash256) -> PathBuf {
+        self.dir.join(format!("{}.json", repository_id))
+    }
+
+    /// Returns the state recorded for the repository, `None` if it was never opened here
+    pub fn load(&self, repository_id: &Hash256) -> Result<Option<TrustedState>> {
+        match fs::read_to_string(self.path(repository_id)) {
+            Ok(state) => Ok(Some(serde_json::from_str(&state)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Fails if `manifest` is older than the last one seen, or a different one of the same generation
+    pub fn check(&self, repository_id: &Hash256, manifest: &Manifest) -> Result<()> {
+        let Some(state) = self.load(repository_id)? else {
+            // nothing to compare against on first use
+            return Ok(());
+        };
+        if manifest.generation < state.generation {
+            return Err(
+                BackrubError::ManifestRollback(state.generation, manifest.generation).into(),
+            );
+        }
+        if manifest.generation == state.generation && manifest.digest() != state.manifest_digest {
+            return Err(BackrubError::ManifestForked(manifest.generation).into());
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::check`], but also accepts the manifest the last seen one replaced
+    ///
+    /// That is the copy at [`manifest_backup_path`](crate::manager::manifest_backup_path), which
+    /// is opened when the manifest is damaged.
+    pub fn check_previous(&self, repository_id: &Hash256, manifest: &Manifest) -> Result<()> {
+        match self.load(repository_id)? {
+            Some(state) if manifest.digest() == state.previous_manifest_hash => Ok(()),
+            _ => self.check(repository_id, manifest),
+        }
+    }
+
+    /// Records `manifest` as the last one seen of the repository
+    pub fn record(&self, repository_id: &Hash256, manifest: &Manifest) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        write_file_atomic(
+            &self.path(repository_id),
+            serde_json::to_string(&TrustedState::from(manifest))?.as_bytes(),
+        )
+    }
+}
@@ -0,0 +1,649 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    io::{prelude::*, ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use walkdir::WalkDir;
+use zeroize::Zeroizing;
+
+use super::error::*;
+use super::traits::*;
+use super::utils::*;
+
+/// Storage of the chunk data, that is pack files and chunk files, under names like `1/a3.bin`
+///
+/// Reading a missing object fails with [`ErrorKind::NotFound`] and reading past its end with
+/// [`ErrorKind::UnexpectedEof`], see [`Error::io_error_kind`].
+pub trait StorageBackend: fmt::Debug + Send + Sync {
+    /// Stores `data` under `name`, an existing object of that name is replaced
+    fn put(&self, name: &Path, data: &[u8]) -> Result<()>;
+
+    /// Reads a whole object
+    fn get(&self, name: &Path) -> Result<Vec<u8>>;
+
+    /// Reads `length` bytes of an object starting at `offset`
+    fn get_range(&self, name: &Path, offset: u64, length: u64) -> Result<Vec<u8>>;
+
+    /// Removes an object, removing a missing object is no error
+    fn delete(&self, name: &Path) -> Result<()>;
+
+    /// Lists the names and sizes of all objects
+    fn list(&self) -> Result<BTreeMap<PathBuf, u64>>;
+
+    /// Returns the size of an object, `None` if it does not exist
+    fn size(&self, name: &Path) -> Result<Option<u64>>;
+
+    /// Removes leftovers of interrupted writes, backends with atomic writes have none
+    fn remove_stale_temp_files(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn exists(&self, name: &Path) -> Result<bool> {
+        Ok(self.size(name)?.is_some())
+    }
+}
+
+/// Storage backend of a repository, it is part of the [`Manifest`](crate::structs::Manifest)
+#[derive(Clone, Hash, Default, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum StorageConf {
+    /// Files below the chunk root directory
+    #[default]
+    Local,
+    /// Objects in a bucket of an S3 compatible object store
+    ///
+    /// The credentials are taken from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`,
+    /// opening the backend fails without them.
+    S3(S3Conf),
+}
+
+impl CanonicalEncode for StorageConf {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        match self {
+            StorageConf::Local => 0u8.encode_canonical(out),
+            StorageConf::S3(conf) => {
+                1u8.encode_canonical(out);
+                conf.encode_canonical(out);
+            }
+        }
+    }
+}
+
+impl StorageConf {
+    /// Opens the backend, local storage is rooted at `chunk_root_dir`
+    pub fn open(&self, chunk_root_dir: &Path) -> Result<Box<dyn StorageBackend>> {
+        match self {
+            StorageConf::Local => Ok(Box::new(LocalBackend::new(chunk_root_dir.to_path_buf()))),
+            StorageConf::S3(conf) => {
+                let credentials = S3Credentials::from_env().ok_or_else(|| {
+                    BackrubError::StorageError(
+                        "S3 credentials are missing, set AWS_ACCESS_KEY_ID and \
+                         AWS_SECRET_ACCESS_KEY"
+                            .to_string(),
+                    )
+                })?;
+                Ok(Box::new(S3Backend::new(conf.clone(), credentials)?))
+            }
+        }
+    }
+}
+
+/// Files below a root directory
+#[derive(Clone, Debug)]
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: PathBuf) -> Self {
+        LocalBackend { root }
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn put(&self, name: &Path, data: &[u8]) -> Result<()> {
+        let path = self.root.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_file_atomic(&path, data)
+    }
+
+    fn get(&self, name: &Path) -> Result<Vec<u8>> {
+        Ok(fs::read(self.root.join(name))?)
+    }
+
+    fn get_range(&self, name: &Path, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let mut file = fs::File::open(self.root.join(name))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        file.take(length).read_to_end(&mut data)?;
+        if data.len() as u64 != length {
+            return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        Ok(data)
+    }
+
+    fn delete(&self, name: &Path) -> Result<()> {
+        match fs::remove_file(self.root.join(name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self) -> Result<BTreeMap<PathBuf, u64>> {
+        let mut objects = BTreeMap::new();
+        for entry in WalkDir::new(&self.root) {
+            let entry = match entry {
+                // a repository without chunks has no root directory yet
+                Err(e)
+                    if e.depth() == 0
+                        && e.io_error().map(|e| e.kind()) == Some(ErrorKind::NotFound) =>
+                {
+                    break
+                }
+                entry => entry.map_err(std::io::Error::from)?,
+            };
+            // temporary files of interrupted writes are no objects
+            if entry.file_type().is_file() && !is_temp_file(entry.path()) {
+                let name = entry
+                    .path()
+                    .strip_prefix(&self.root)
+                    .expect("this can not fail because all entries are below the root");
+                objects.insert(
+                    name.to_path_buf(),
+                    entry.metadata().map_err(std::io::Error::from)?.len(),
+                );
+            }
+        }
+        Ok(objects)
+    }
+
+    fn size(&self, name: &Path) -> Result<Option<u64>> {
+        match fs::metadata(self.root.join(name)) {
+            Ok(metadata) if metadata.is_file() => Ok(Some(metadata.len())),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn remove_stale_temp_files(&self) -> Result<()> {
+        remove_stale_temp_files(&self.root, true)?;
+        Ok(())
+    }
+}
+
+/// Objects in memory, for tests
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    objects: Mutex<BTreeMap<PathBuf, Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn put(&self, name: &Path, data: &[u8]) -> Result<()> {
+        self.objects
+            .lock()
+            .expect("a thread panicked while holding the lock")
+            .insert(name.to_path_buf(), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, name: &Path) -> Result<Vec<u8>> {
+        self.objects
+            .lock()
+            .expect("a thread panicked while holding the lock")
+            .get(name)
+            .cloned()
+            .ok_or_else(|| std::io::Error::from(ErrorKind::NotFound).into())
+    }
+
+    fn get_range(&self, name: &Path, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let data = self.get(name)?;
+        offset
+            .checked_add(length)
+            .and_then(|end| data.get(offset as usize..end as usize))
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| std::io::Error::from(ErrorKind::UnexpectedEof).into())
+    }
+
+    fn delete(&self, name: &Path) -> Result<()> {
+        self.objects
+            .lock()
+            .expect("a thread panicked while holding the lock")
+            .remove(name);
+        Ok(())
+    }
+
+    fn list(&self) -> Result<BTreeMap<PathBuf, u64>> {
+        Ok(self
+            .objects
+            .lock()
+            .expect("a thread panicked while holding the lock")
+            .iter()
+            .map(|(name, data)| (name.clone(), data.len() as u64))
+            .collect())
+    }
+
+    fn size(&self, name: &Path) -> Result<Option<u64>> {
+        Ok(self
+            .objects
+            .lock()
+            .expect("a thread panicked while holding the lock")
+            .get(name)
+            .map(|data| data.len() as u64))
+    }
+}
+
+/// Location of the objects of a repository in an S3 compatible object store
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct S3Conf {
+    /// Base URL of the service like `https://s3.eu-central-1.amazonaws.com`, buckets are
+    /// addressed by path
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    /// Prepended to the names of all objects, like `backups/`
+    pub prefix: String,
+}
+
+impl CanonicalEncode for S3Conf {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        self.endpoint.encode_canonical(out);
+        self.region.encode_canonical(out);
+        self.bucket.encode_canonical(out);
+        self.prefix.encode_canonical(out);
+    }
+}
+
+/// Access key of an S3 compatible object store
+#[derive(Clone)]
+pub struct S3Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: Zeroizing<String>,
+}
+
+impl fmt::Debug for S3Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .field("secret_access_key", &"<redacted>")
+            .finish()
+    }
+}
+
+impl S3Credentials {
+    /// Reads `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`, `None` if one is not set
+    pub fn from_env() -> Option<Self> {
+        Some(S3Credentials {
+            access_key_id: env::var("AWS_ACCESS_KEY_ID").ok()?,
+            secret_access_key: Zeroizing::new(env::var("AWS_SECRET_ACCESS_KEY").ok()?),
+        })
+    }
+}
+
+/// Hash of an empty payload
+const EMPTY_PAYLOAD_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// Objects in an S3 compatible object store, requests are signed with AWS signature version 4
+#[derive(Debug)]
+pub struct S3Backend {
+    conf: S3Conf,
+    credentials: S3Credentials,
+    /// Authority of [`S3Conf::endpoint`], it is signed as the host header
+    host: String,
+    agent: ureq::Agent,
+}
+
+impl S3Backend {
+    pub fn new(conf: S3Conf, credentials: S3Credentials) -> Result<Self> {
+        let invalid = |reason: &str| BackrubError::StorageError(reason.to_string());
+
+        let (scheme, rest) = conf
+            .endpoint
+            .split_once("://")
+            .ok_or_else(|| invalid("the S3 endpoint is no URL"))?;
+        let authority = rest.trim_end_matches('/');
+        // clients leave out default ports in the host header
+        let host = match scheme {
+            "http" => authority.strip_suffix(":80").unwrap_or(authority),
+            "https" => authority.strip_suffix(":443").unwrap_or(authority),
+            _ => return Err(invalid("the S3 endpoint must be a http or https URL").into()),
+        };
+        if host.is_empty() || host.contains('/') {
+            return Err(invalid("the S3 endpoint must not have a path").into());
+        }
+        if conf.bucket.is_empty() || conf.bucket.contains('/') {
+            return Err(invalid("the S3 bucket is not valid").into());
+        }
+
+        Ok(S3Backend {
+            host: host.to_string(),
+            conf,
+            credentials,
+            agent: ureq::AgentBuilder::new().build(),
+        })
+    }
+
+    fn object_path(&self, name: &Path) -> String {
+        format!(
+            "/{}/{}",
+            uri_encode(&self.conf.bucket, true),
+            uri_encode(&format!("{}{}", self.conf.prefix, name.display()), false)
+        )
+    }
+
+    /// Sends a signed request, `query` has to be sorted and encoded like [`uri_encode`] does
+    #[allow(clippy::result_large_err)]
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        query: &str,
+        range: Option<(u64, u64)>,
+        payload: &[u8],
+    ) -> std::result::Result<ureq::Response, ureq::Error> {
+        let url = match query.is_empty() {
+            true => format!("{}{}", self.conf.endpoint.trim_end_matches('/'), path),
+            false => format!(
+                "{}{}?{}",
+                self.conf.endpoint.trim_end_matches('/'),
+                path,
+                query
+            ),
+        };
+        let payload_hash = match payload.is_empty() {
+            true => EMPTY_PAYLOAD_HASH.to_string(),
+            false => hex(&Sha256::digest(payload)),
+        };
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+
+        let mut headers = vec![("host", self.host.clone())];
+        if let Some((offset, length)) = range {
+            headers.push(("range", format!("bytes={}-{}", offset, offset + length - 1)));
+        }
+        headers.push(("x-amz-content-sha256", payload_hash.clone()));
+        headers.push(("x-amz-date", amz_date.clone()));
+
+        let mut request = self.agent.request(method, &url);
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.set(name, value);
+        }
+        let authorization = s3_authorization(
+            &self.credentials,
+            &self.conf.region,
+            &amz_date,
+            &SignedRequest {
+                method,
+                path,
+                query,
+                headers: &headers,
+                payload_hash: &payload_hash,
+            },
+        );
+        request = request.set("authorization", &authorization);
+
+        match payload.is_empty() {
+            true => request.call(),
+            false => request.send_bytes(payload),
+        }
+    }
+
+    /// Turns a failed request for `name` into an [`Error`], missing objects into [`ErrorKind::NotFound`]
+    fn error(method: &str, name: &Path, error: ureq::Error) -> Error {
+        match error {
+            ureq::Error::Status(404, _) => std::io::Error::from(ErrorKind::NotFound).into(),
+            ureq::Error::Status(416, _) => std::io::Error::from(ErrorKind::UnexpectedEof).into(),
+            ureq::Error::Status(code, _) => BackrubError::StorageError(format!(
+                "{} \"{}\" failed with status {}",
+                method,
+                name.display(),
+                code
+            ))
+            .into(),
+            ureq::Error::Transport(transport) => BackrubError::StorageError(format!(
+                "{} \"{}\" failed: {}",
+                method,
+                name.display(),
+                transport
+            ))
+            .into(),
+        }
+    }
+}
+
+impl StorageBackend for S3Backend {
+    fn put(&self, name: &Path, data: &[u8]) -> Result<()> {
+        self.request("PUT", &self.object_path(name), "", None, data)
+            .map_err(|e| Self::error("PUT", name, e))?;
+        Ok(())
+    }
+
+    fn get(&self, name: &Path) -> Result<Vec<u8>> {
+        let response = self
+            .request("GET", &self.object_path(name), "", None, &[])
+            .map_err(|e| Self::error("GET", name, e))?;
+        let mut data = Vec::new();
+        response.into_reader().read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn get_range(&self, name: &Path, offset: u64, length: u64) -> Result<Vec<u8>> {
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+        let response = self
+            .request(
+                "GET",
+                &self.object_path(name),
+                "",
+                Some((offset, length)),
+                &[],
+            )
+            .map_err(|e| Self::error("GET", name, e))?;
+
+        // servers without range support send the whole object
+        let whole_object = response.status() == 200;
+        let mut data = Vec::new();
+        response.into_reader().read_to_end(&mut data)?;
+        if whole_object {
+            data = offset
+                .checked_add(length)
+                .and_then(|end| data.get(offset as usize..end as usize))
+                .map(<[u8]>::to_vec)
+                .unwrap_or_default();
+        }
+        if data.len() as u64 != length {
+            return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        Ok(data)
+    }
+
+    fn delete(&self, name: &Path) -> Result<()> {
+        match self.request("DELETE", &self.object_path(name), "", None, &[]) {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(()),
+            Err(e) => Err(Self::error("DELETE", name, e)),
+        }
+    }
+
+    fn list(&self) -> Result<BTreeMap<PathBuf, u64>> {
+        let mut objects = BTreeMap::new();
+        let path = format!("/{}", uri_encode(&self.conf.bucket, true));
+        let mut continuation_token = None::<String>;
+        loop {
+            // the parameters are sorted for the signature
+            let mut query = String::new();
+            if let Some(token) = &continuation_token {
+                query += &format!("continuation-token={}&", uri_encode(token, true));
+            }
+            query += &format!("list-type=2&prefix={}", uri_encode(&self.conf.prefix, true));
+
+            let response = self
+                .request("GET", &path, &query, None, &[])
+                .map_err(|e| Self::error("LIST", Path::new(&self.conf.prefix), e))?;
+            let mut xml = String::new();
+            response.into_reader().read_to_string(&mut xml)?;
+
+            for contents in xml_elements(&xml, "Contents") {
+                let key = xml_elements(contents, "Key").next().map(xml_unescape);
+                let size = xml_elements(contents, "Size").next();
+                match (key, size.and_then(|size| size.parse::<u64>().ok())) {
+                    (Some(key), Some(size)) => {
+                        if let Some(name) = key.strip_prefix(&self.conf.prefix) {
+                            objects.insert(PathBuf::from(name), size);
+                        }
+                    }
+                    _ => {
+                        return Err(BackrubError::StorageError(
+                            "the object list is not valid".to_string(),
+                        )
+                        .into())
+                    }
+                }
+            }
+
+            if xml_elements(&xml, "IsTruncated").next() != Some("true") {
+                return Ok(objects);
+            }
+            continuation_token = xml_elements(&xml, "NextContinuationToken")
+                .next()
+                .map(xml_unescape);
+            if continuation_token.is_none() {
+                return Err(BackrubError::StorageError(
+                    "the object list is truncated without continuation token".to_string(),
+                )
+                .into());
+            }
+        }
+    }
+
+    fn size(&self, name: &Path) -> Result<Option<u64>> {
+        match self.request("HEAD", &self.object_path(name), "", None, &[]) {
+            Ok(response) => match response
+                .header("content-length")
+                .and_then(|length| length.parse().ok())
+            {
+                Some(length) => Ok(Some(length)),
+                None => Err(BackrubError::StorageError(format!(
+                    "HEAD \"{}\" returned no content length",
+                    name.display()
+                ))
+                .into()),
+            },
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(Self::error("HEAD", name, e)),
+        }
+    }
+}
+
+/// Parts of a request that are covered by an AWS signature version 4
+pub(crate) struct SignedRequest<'a> {
+    pub method: &'a str,
+    /// Encoded path
+    pub path: &'a str,
+    /// Encoded query with sorted parameters
+    pub query: &'a str,
+    /// Lowercase names and values of the signed headers sorted by name, including `host`
+    pub headers: &'a [(&'a str, String)],
+    pub payload_hash: &'a str,
+}
+
+/// Builds the authorization header of a request at `amz_date` like `20130524T000000Z`
+pub(crate) fn s3_authorization(
+    credentials: &S3Credentials,
+    region: &str,
+    amz_date: &str,
+    request: &SignedRequest,
+) -> String {
+    let mut canonical_request =
+        format!("{}\n{}\n{}\n", request.method, request.path, request.query);
+    for (name, value) in request.headers {
+        canonical_request += &format!("{}:{}\n", name, value.trim());
+    }
+    let signed_headers = request
+        .headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    canonical_request += &format!("\n{}\n{}", signed_headers, request.payload_hash);
+
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let secret = Zeroizing::new(format!("AWS4{}", credentials.secret_access_key.as_str()));
+    let mut key = hmac_sha256(secret.as_bytes(), date.as_bytes());
+    for part in [region, "s3", "aws4_request"] {
+        key = hmac_sha256(&key, part.as_bytes());
+    }
+    let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id, scope, signed_headers, signature
+    )
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    Zeroizing::new(mac.finalize().into_bytes().to_vec())
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Percent-encodes everything but unreserved characters, `/` only if `encode_slash` is set
+pub(crate) fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded += &format!("%{:02X}", byte),
+        }
+    }
+    encoded
+}
+
+/// Contents of all elements `<tag>...</tag>`, the responses of S3 do not nest equal tags
+fn xml_elements<'a>(xml: &'a str, tag: &str) -> impl Iterator<Item = &'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut rest = xml;
+    std::iter::from_fn(move || {
+        let start = rest.find(&open)? + open.len();
+        let end = start + rest[start..].find(&close)?;
+        let contents = &rest[start..end];
+        rest = &rest[end + close.len()..];
+        Some(contents)
+    })
+}
+
+fn xml_unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
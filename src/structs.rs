@@ -1,7 +1,17 @@
 use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use flate2::write::{DeflateDecoder, DeflateEncoder};
 use generic_array::GenericArray;
 use serde::{Deserialize, Serialize};
-use std::{os::unix::prelude::MetadataExt, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::prelude::*,
+    ops::Deref,
+    os::unix::prelude::{MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 use typenum::{
     bit::{B0, B1},
     uint::{UInt, UTerm},
@@ -9,23 +19,69 @@ use typenum::{
 
 use super::db::*;
 use super::error::*;
+use super::state::*;
+use super::storage::*;
 use super::traits::*;
 use super::utils::*;
+use zeroize::{Zeroize, Zeroizing};
 
 pub const SALT_SIZE: usize = 32;
 pub const HASH_SIZE: usize = 32;
 pub const KEY_SIZE: usize = 32;
 pub const NONCE_SIZE: usize = 24;
-pub const CRYPTO_KEYS_SIZE: usize = KEY_SIZE * 4;
+pub const CRYPTO_KEYS_SIZE: usize = KEY_SIZE * 6;
+pub const MANIFEST_FORMAT_VERSION: u32 = 10;
+/// First manifest format version that is signed over [`Manifest::signed_bytes`] instead of bincode
+pub const CANONICAL_SIGNATURE_FORMAT_VERSION: u32 = 4;
+/// First manifest format version with [`Manifest::generation`] and [`Manifest::previous_manifest_hash`]
+pub const GENERATION_FORMAT_VERSION: u32 = 5;
+/// First manifest format version with [`Manifest::key_pair`]
+pub const KEY_PAIR_FORMAT_VERSION: u32 = 6;
+/// First manifest format version with [`Manifest::compression`]
+pub const COMPRESSION_FORMAT_VERSION: u32 = 7;
+/// First manifest format version with [`Manifest::max_pack_size`]
+pub const PACK_FORMAT_VERSION: u32 = 8;
+/// First manifest format version with [`Manifest::storage`]
+pub const STORAGE_FORMAT_VERSION: u32 = 9;
+/// First manifest format version with [`SignedManifest::write_only_signature`] and a files cache
+/// whose keys write-only clients do not know
+pub const WRITE_ONLY_SIGNATURE_FORMAT_VERSION: u32 = 10;
+/// Version of the layout built by [`Manifest::signed_bytes`]
+pub const MANIFEST_SIGNATURE_VERSION: u32 = 1;
+/// Context of the key derived from [`CryptoKeys`]' manifest signature key for signing manifests
+const MANIFEST_SIGNATURE_CONTEXT: &str = "backrub manifest signature v1";
+/// Context of the key write-only clients verify manifests with, see [`write_only_manifest_key`]
+const WRITE_ONLY_MANIFEST_CONTEXT: &str = "backrub write-only manifest signature v1";
+/// Context of the repository id, see [`CryptoKeys::repository_id`]
+const REPOSITORY_ID_CONTEXT: &str = "backrub repository id v2";
 
-// Signature key for manifest + encrypted keys for data
+// Legacy key slot verification key + key encryption keys
 pub const TOTAL_KEY_SIZE: usize = KEY_SIZE + CRYPTO_KEYS_SIZE;
+/// Size of the keys wrapped by manifests without key slots, see [`EncCryptoKeysV0`]
+pub const CRYPTO_KEYS_SIZE_V0: usize = KEY_SIZE * 4;
 
 pub type RefCount = usize;
 
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
+#[derive(
+    Debug, Copy, Clone, Default, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize,
+)]
 pub struct Hash256(pub(crate) [u8; HASH_SIZE]);
 
+impl fmt::Display for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl CanonicalEncode for Hash256 {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        self.0.encode_canonical(out);
+    }
+}
+
 impl From<[u8; HASH_SIZE]> for Hash256 {
     fn from(array: [u8; HASH_SIZE]) -> Self {
         Hash256(array)
@@ -56,9 +112,28 @@ impl AsRef<[u8]> for Hash256 {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
+/// A 256 bit key, wiped from memory when dropped and never printed by [`fmt::Debug`]
+#[derive(Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
 pub struct Key256(pub(crate) [u8; KEY_SIZE]);
 
+impl fmt::Debug for Key256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key256(<redacted>)")
+    }
+}
+
+impl Zeroize for Key256 {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for Key256 {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
 impl From<GenericArray<u8, UInt<UInt<UInt<UInt<UInt<UInt<UTerm, B1>, B0>, B0>, B0>, B0>, B0>>>
     for Key256
 {
@@ -75,15 +150,12 @@ impl Key256 {
     }
 
     pub fn xor_keys(&self, key: &Key256) -> Key256 {
-        let l = self.as_array();
-        let r = key.as_array();
-        let l_iter = l.iter();
-        let r_iter = r.iter();
-        let result: Vec<u8> = l_iter.zip(r_iter).map(|(l, r)| l ^ r).collect();
-        let result: [u8; KEY_SIZE] = result
-            .try_into()
-            .expect("This can never fail because all length match");
-        Key256::from(result)
+        // build the result in place, so no copy of it is left behind
+        let mut result = Key256([0u8; KEY_SIZE]);
+        for ((result, l), r) in result.0.iter_mut().zip(self.0.iter()).zip(key.0.iter()) {
+            *result = l ^ r;
+        }
+        result
     }
 }
 
@@ -112,6 +184,83 @@ impl AsRef<[u8]> for Key256 {
     }
 }
 
+/// Key material on the heap, kept out of swap where the system allows it and wiped when dropped
+///
+/// Locking the pages is best effort, it fails silently e.g. if `RLIMIT_MEMLOCK` is exhausted.
+pub struct Locked<T: Zeroize>(Box<T>);
+
+/// Number of [`Locked`] values on every locked page, as `munlock` does not nest
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+impl<T: Zeroize> Locked<T> {
+    pub fn new(value: T) -> Self {
+        let locked = Locked(Box::new(value));
+        locked.lock_pages(true);
+        locked
+    }
+
+    /// Locks or unlocks every page of the value that no other [`Locked`] value uses
+    fn lock_pages(&self, lock: bool) {
+        let size = std::mem::size_of::<T>();
+        if size == 0 {
+            return;
+        }
+        // Safety: sysconf has no preconditions
+        let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+            page_size if page_size > 0 => page_size as usize,
+            _ => return,
+        };
+        let start = &*self.0 as *const T as usize;
+        let first_page = start - start % page_size;
+        let last_page = (start + size - 1) - (start + size - 1) % page_size;
+
+        let mut locked_pages = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+        for page in (first_page..=last_page).step_by(page_size) {
+            let count = locked_pages.entry(page).or_insert(0);
+            // Safety: the page is mapped, it holds part of the boxed value
+            if lock {
+                if *count == 0 {
+                    unsafe { libc::mlock(page as *const libc::c_void, page_size) };
+                }
+                *count += 1;
+            } else {
+                *count -= 1;
+                if *count == 0 {
+                    locked_pages.remove(&page);
+                    unsafe { libc::munlock(page as *const libc::c_void, page_size) };
+                }
+            }
+        }
+    }
+}
+
+impl<T: Zeroize> Drop for Locked<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        self.lock_pages(false);
+    }
+}
+
+impl<T: Zeroize> Deref for Locked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Locked<T> {
+    fn clone(&self) -> Self {
+        Locked::new((*self.0).clone())
+    }
+}
+
+impl<T: Zeroize + fmt::Debug> fmt::Debug for Locked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
 pub struct Nonce192(pub(crate) [u8; NONCE_SIZE]);
 
@@ -154,19 +303,232 @@ impl AsRef<[u8]> for Nonce192 {
     }
 }
 
+/// X25519 public key of a repository in write-only mode
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
+pub struct PublicKey256(pub(crate) [u8; KEY_SIZE]);
+
+impl PublicKey256 {
+    pub fn as_array(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+
+    /// Computes the public key belonging to an X25519 secret key
+    pub fn from_secret_key(secret_key: &Key256) -> Self {
+        let secret_key = x25519_dalek::StaticSecret::from(*secret_key.as_array());
+        PublicKey256(x25519_dalek::PublicKey::from(&secret_key).to_bytes())
+    }
+}
+
+impl From<[u8; KEY_SIZE]> for PublicKey256 {
+    fn from(array: [u8; KEY_SIZE]) -> Self {
+        PublicKey256(array)
+    }
+}
+
+impl CanonicalEncode for PublicKey256 {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        self.0.encode_canonical(out);
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct BackupConf {
-    follow_symlinks: bool,
+    pub follow_symlinks: bool,
+    /// Verify already stored chunks against the source data and rewrite missing or corrupt chunk files
+    ///
+    /// This implies `force_rehash`
+    pub repair: bool,
+    /// Read every file even if the files cache says it is unchanged
+    pub force_rehash: bool,
+    pub compression_policy: CompressionPolicy,
 }
 
 impl Default for BackupConf {
     fn default() -> Self {
         BackupConf {
             follow_symlinks: false,
+            repair: false,
+            force_rehash: false,
+            compression_policy: CompressionPolicy::default(),
+        }
+    }
+}
+
+/// Decides for every new chunk whether compressing it is worth the time
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CompressionPolicy {
+    /// Compress every chunk with the compression of the repository
+    Always,
+    /// Trial-compress samples of every chunk and store it uncompressed if they shrink by less
+    /// than `min_savings_percent`
+    Adaptive { min_savings_percent: u8 },
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        CompressionPolicy::Adaptive {
+            min_savings_percent: 5,
+        }
+    }
+}
+
+impl CompressionPolicy {
+    /// Size of each sample that is trial-compressed
+    const SAMPLE_SIZE: usize = 64 * 1024;
+    /// Number of samples spread evenly over a chunk, smaller chunks are compressed as a whole
+    const SAMPLES: usize = 4;
+
+    /// Returns the compression to store `data` with, [`Compression::None`] if it is not worth it
+    pub fn choose(&self, compression: Compression, data: &[u8]) -> Result<Compression> {
+        let min_savings_percent = match self {
+            CompressionPolicy::Always => return Ok(compression),
+            CompressionPolicy::Adaptive {
+                min_savings_percent,
+            } => *min_savings_percent as usize,
+        };
+        if compression == Compression::None || data.is_empty() {
+            return Ok(Compression::None);
+        }
+
+        let samples: Vec<&[u8]> = if data.len() <= Self::SAMPLE_SIZE * Self::SAMPLES {
+            vec![data]
+        } else {
+            let last_start = data.len() - Self::SAMPLE_SIZE;
+            (0..Self::SAMPLES)
+                .map(|i| last_start * i / (Self::SAMPLES - 1))
+                .map(|start| &data[start..start + Self::SAMPLE_SIZE])
+                .collect()
+        };
+
+        let mut original = 0;
+        let mut compressed = 0;
+        for sample in samples {
+            original += sample.len();
+            compressed += compression.compress(sample)?.len();
+        }
+
+        let savings = original.saturating_sub(compressed);
+        if savings * 100 < original * min_savings_percent {
+            Ok(Compression::None)
+        } else {
+            Ok(compression)
         }
     }
 }
 
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RestoreConf {
+    /// Overwrite files and symlinks that already exist in the target directory
+    pub overwrite: bool,
+    /// Restore uid and gid, this usually requires root privileges
+    pub restore_ownership: bool,
+}
+
+/// Options for opening an existing repository
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OpenConf {
+    /// Directory of the [`TrustedStateStore`] that protects against rolled back manifests
+    ///
+    /// `None` disables the protection.
+    pub trusted_state_dir: Option<PathBuf>,
+}
+
+impl Default for OpenConf {
+    fn default() -> Self {
+        OpenConf {
+            trusted_state_dir: TrustedStateStore::default_dir(),
+        }
+    }
+}
+
+/// Summary of a restore operation
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct RestoreReport {
+    pub files: usize,
+    pub directories: usize,
+    pub symlinks: usize,
+    /// Files that were **not** restored because their content did not match [`File::file_hash`]
+    pub hash_mismatches: Vec<PathBuf>,
+    /// Files that were **not** restored because one of their chunks is missing or corrupt
+    pub unreadable_chunks: Vec<PathBuf>,
+}
+
+impl RestoreReport {
+    /// Returns `true` if every file could be restored correctly
+    pub fn is_ok(&self) -> bool {
+        self.hash_mismatches.is_empty() && self.unreadable_chunks.is_empty()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CheckLevel {
+    /// Check that all references are valid, all chunk files exist and all reference counts are correct
+    Structure,
+    /// Additionally read, decrypt and re-hash every chunk file
+    Data,
+}
+
+/// Findings of a repository check
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct CheckReport {
+    /// Inodes referenced by a backup or directory that are not in the inode database
+    pub missing_inodes: Vec<Hash256>,
+    /// Chunks referenced by a file that are not in the chunk database
+    pub missing_chunks: Vec<Hash256>,
+    /// Chunks in the chunk database whose chunk file does not exist
+    pub missing_chunk_files: Vec<(Hash256, PathBuf)>,
+    /// Chunks whose file could not be decrypted or does not match the chunk hash,
+    /// only checked with [`CheckLevel::Data`]
+    pub corrupt_chunks: Vec<(Hash256, PathBuf)>,
+    /// Inodes in the inode database that are not referenced by any backup
+    pub orphaned_inodes: Vec<Hash256>,
+    /// Chunks in the chunk database that are not referenced by any file
+    pub orphaned_chunks: Vec<Hash256>,
+    /// Files in the chunk root directory that do not belong to any chunk
+    pub orphaned_chunk_files: Vec<PathBuf>,
+    /// Pack files without referenced chunks, their space is reclaimed by
+    /// [`BackupManager::repack`](crate::manager::BackupManager::repack), this is no problem
+    pub unused_packs: Vec<PathBuf>,
+    /// Pack files whose index could not be decrypted or does not list all chunks stored in them,
+    /// only checked with [`CheckLevel::Data`]
+    pub corrupt_pack_indexes: Vec<PathBuf>,
+    /// Inodes as `(key, stored ref count, real ref count)`
+    pub wrong_inode_ref_counts: Vec<(Hash256, RefCount, RefCount)>,
+    /// Chunks as `(key, stored ref count, real ref count)`
+    pub wrong_chunk_ref_counts: Vec<(Hash256, RefCount, RefCount)>,
+}
+
+impl CheckReport {
+    /// Returns `true` if no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.missing_inodes.is_empty()
+            && self.missing_chunks.is_empty()
+            && self.missing_chunk_files.is_empty()
+            && self.corrupt_chunks.is_empty()
+            && self.orphaned_inodes.is_empty()
+            && self.orphaned_chunks.is_empty()
+            && self.orphaned_chunk_files.is_empty()
+            && self.corrupt_pack_indexes.is_empty()
+            && self.wrong_inode_ref_counts.is_empty()
+            && self.wrong_chunk_ref_counts.is_empty()
+    }
+}
+
+/// Result of [`BackupManager::repack`](crate::manager::BackupManager::repack)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct RepackReport {
+    /// Pack files and unpacked chunk files whose chunks were copied into new packs
+    pub files_rewritten: u64,
+    /// Chunks that were copied
+    pub chunks_moved: u64,
+    /// Files that were removed, rewritten ones and those without referenced chunks
+    pub files_removed: u64,
+    /// Size of the removed files
+    pub bytes_removed: u64,
+    /// Size of the copied chunks
+    pub bytes_written: u64,
+}
+
 /*
 #[derive(Clone, Default, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct RuntimeConf {
@@ -189,6 +551,9 @@ pub struct Metadata {
     pub mtime_ns: i64,
     pub ctime: i64,
     pub ctime_ns: i64,
+    pub dev: u64,
+    pub ino: u64,
+    pub size: u64,
 }
 
 impl From<std::fs::Metadata> for Metadata {
@@ -203,6 +568,9 @@ impl From<std::fs::Metadata> for Metadata {
             mtime_ns: value.mtime_nsec(),
             ctime: value.ctime(),
             ctime_ns: value.ctime_nsec(),
+            dev: value.dev(),
+            ino: value.ino(),
+            size: value.size(),
         }
     }
 }
@@ -214,35 +582,167 @@ pub struct ChunkerConf {
     pub maximum_chunk_size: u64,
 }
 
+impl CanonicalEncode for ChunkerConf {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        self.minimum_chunk_size.encode_canonical(out);
+        self.average_chunk_size.encode_canonical(out);
+        self.maximum_chunk_size.encode_canonical(out);
+    }
+}
+
+/// Compression of chunk data before it is encrypted
+///
+/// The algorithm is recorded in every encrypted blob, so changing it never affects reading
+/// existing data.
 #[derive(Clone, Hash, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// Used by everything written before the algorithm was recorded
+    Deflate,
+    Lz4,
+    /// Zstandard with a compression level, negative levels trade ratio for speed
+    Zstd(i32),
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Zstd(3)
+    }
+}
+
+impl CanonicalEncode for Compression {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        match self {
+            Compression::None => 0u8.encode_canonical(out),
+            Compression::Deflate => 1u8.encode_canonical(out),
+            Compression::Lz4 => 2u8.encode_canonical(out),
+            Compression::Zstd(level) => {
+                3u8.encode_canonical(out);
+                (*level as u32).encode_canonical(out);
+            }
+        }
+    }
+}
+
+impl Compression {
+    pub fn validate(&self) -> Result<()> {
+        if let Compression::Zstd(level) = self {
+            let levels = zstd::compression_level_range();
+            if !levels.contains(level) {
+                return Err(BackrubError::InvalidCompression(format!(
+                    "zstd level must be between {} and {}, not {}",
+                    levels.start(),
+                    levels.end(),
+                    level
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Deflate => {
+                let mut compressor =
+                    DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                compressor.write_all(data)?;
+                Ok(compressor.finish()?)
+            }
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Compression::Zstd(level) => Ok(zstd::bulk::compress(data, *level)?),
+        }
+    }
+
+    /// Reverses [`Compression::compress`], the level of zstd does not matter here
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Deflate => {
+                let mut deflater = DeflateDecoder::new(Vec::new());
+                deflater.write_all(data)?;
+                Ok(deflater.finish()?)
+            }
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e).into()),
+            Compression::Zstd(_) => Ok(zstd::decode_all(data)?),
+        }
+    }
+}
+
+/// [`CryptoKeys`] XORed with [`KeyEncryptionKeys`], as stored by manifest format version 1
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct EncCryptoKeys {
     enc_chunk_hash_key: Key256,
     enc_chunk_enc_key: Key256,
     enc_inode_hash_key: Key256,
     enc_inode_enc_key: Key256,
+    enc_backup_enc_key: Key256,
+    enc_manifest_sig_key: Key256,
 }
 
 impl EncCryptoKeys {
-    pub fn decrypt(&self, keys: KeyEncryptionKeys) -> CryptoKeys {
+    pub fn xor_unwrap(&self, keys: &KeyEncryptionKeys) -> CryptoKeys {
         CryptoKeys {
             chunk_hash_key: self.enc_chunk_hash_key.xor_keys(&keys.key_chunk_hash_key),
             chunk_enc_key: self.enc_chunk_enc_key.xor_keys(&keys.key_chunk_enc_key),
             inode_hash_key: self.enc_inode_hash_key.xor_keys(&keys.key_inode_hash_key),
             inode_enc_key: self.enc_inode_enc_key.xor_keys(&keys.key_inode_enc_key),
+            backup_enc_key: self.enc_backup_enc_key.xor_keys(&keys.key_backup_enc_key),
+            manifest_sig_key: self
+                .enc_manifest_sig_key
+                .xor_keys(&keys.key_manifest_sig_key),
+        }
+    }
+
+    /// Recovers the key encryption keys these keys were wrapped with
+    pub fn key_encryption_keys(&self, keys: &CryptoKeys) -> KeyEncryptionKeys {
+        KeyEncryptionKeys {
+            key_chunk_hash_key: self.enc_chunk_hash_key.xor_keys(&keys.chunk_hash_key),
+            key_chunk_enc_key: self.enc_chunk_enc_key.xor_keys(&keys.chunk_enc_key),
+            key_inode_hash_key: self.enc_inode_hash_key.xor_keys(&keys.inode_hash_key),
+            key_inode_enc_key: self.enc_inode_enc_key.xor_keys(&keys.inode_enc_key),
+            key_backup_enc_key: self.enc_backup_enc_key.xor_keys(&keys.backup_enc_key),
+            key_manifest_sig_key: self.enc_manifest_sig_key.xor_keys(&keys.manifest_sig_key),
         }
     }
 }
 
-#[derive(Clone, Hash, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+/// Data keys XORed with [`KeyEncryptionKeys`], as stored by manifests without key slots
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EncCryptoKeysV0 {
+    enc_chunk_hash_key: Key256,
+    enc_chunk_enc_key: Key256,
+    enc_inode_hash_key: Key256,
+    enc_inode_enc_key: Key256,
+}
+
+impl EncCryptoKeysV0 {
+    /// Unwraps the data keys, the backup and manifest signature keys did not exist yet
+    /// and are generated
+    pub fn xor_unwrap(&self, keys: &KeyEncryptionKeys) -> CryptoKeys {
+        let mut crypto_keys = CryptoKeys::new();
+        crypto_keys.chunk_hash_key = self.enc_chunk_hash_key.xor_keys(&keys.key_chunk_hash_key);
+        crypto_keys.chunk_enc_key = self.enc_chunk_enc_key.xor_keys(&keys.key_chunk_enc_key);
+        crypto_keys.inode_hash_key = self.enc_inode_hash_key.xor_keys(&keys.key_inode_hash_key);
+        crypto_keys.inode_enc_key = self.enc_inode_enc_key.xor_keys(&keys.key_inode_enc_key);
+        crypto_keys
+    }
+}
+
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct KeyEncryptionKeys {
     pub(crate) key_chunk_hash_key: Key256,
     pub(crate) key_chunk_enc_key: Key256,
     pub(crate) key_inode_hash_key: Key256,
     pub(crate) key_inode_enc_key: Key256,
+    pub(crate) key_backup_enc_key: Key256,
+    pub(crate) key_manifest_sig_key: Key256,
 }
 
-impl From<[u8; CRYPTO_KEYS_SIZE]> for KeyEncryptionKeys {
-    fn from(keys: [u8; CRYPTO_KEYS_SIZE]) -> Self {
+impl From<&[u8; CRYPTO_KEYS_SIZE]> for KeyEncryptionKeys {
+    fn from(keys: &[u8; CRYPTO_KEYS_SIZE]) -> Self {
         let mut n = KEY_SIZE;
         let key_chunk_hash_key = Key256::try_from(&keys[n - KEY_SIZE..n])
             .expect("This can not fail because we take care of the correct size here");
@@ -255,28 +755,83 @@ impl From<[u8; CRYPTO_KEYS_SIZE]> for KeyEncryptionKeys {
         n += KEY_SIZE;
         let key_inode_enc_key = Key256::try_from(&keys[n - KEY_SIZE..n])
             .expect("This can not fail because we take care of the correct size here");
+        n += KEY_SIZE;
+        let key_backup_enc_key = Key256::try_from(&keys[n - KEY_SIZE..n])
+            .expect("This can not fail because we take care of the correct size here");
+        n += KEY_SIZE;
+        let key_manifest_sig_key = Key256::try_from(&keys[n - KEY_SIZE..n])
+            .expect("This can not fail because we take care of the correct size here");
 
         KeyEncryptionKeys {
             key_chunk_hash_key,
             key_chunk_enc_key,
             key_inode_hash_key,
             key_inode_enc_key,
+            key_backup_enc_key,
+            key_manifest_sig_key,
         }
     }
 }
 
-#[derive(Clone, Hash, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+impl KeyEncryptionKeys {
+    /// Derives the key that wraps the [`CryptoKeys`] of a key slot
+    pub fn wrapping_key(&self) -> Key256 {
+        let mut hasher = blake3::Hasher::new_derive_key("backrub key slot wrapping key v2");
+        for key in [
+            &self.key_chunk_hash_key,
+            &self.key_chunk_enc_key,
+            &self.key_inode_hash_key,
+            &self.key_inode_enc_key,
+            &self.key_backup_enc_key,
+            &self.key_manifest_sig_key,
+        ] {
+            hasher.update(key.as_array());
+        }
+        Key256::from(*hasher.finalize().as_bytes())
+    }
+}
+
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct CryptoKeys {
     pub(crate) chunk_hash_key: Key256,
     pub(crate) chunk_enc_key: Key256,
     pub(crate) inode_hash_key: Key256,
     pub(crate) inode_enc_key: Key256,
+    pub(crate) backup_enc_key: Key256,
+    pub(crate) manifest_sig_key: Key256,
 }
 
 impl CryptoKeys {
     pub fn new() -> Self {
         let mut keys = [0u8; CRYPTO_KEYS_SIZE];
         OsRng.fill_bytes(&mut keys);
+        let crypto_keys = CryptoKeys::from(&keys);
+        keys.zeroize();
+        crypto_keys
+    }
+
+    /// The keys in the order [`CryptoKeys::from`] expects them
+    pub(crate) fn to_bytes(&self) -> Zeroizing<[u8; CRYPTO_KEYS_SIZE]> {
+        let mut bytes = Zeroizing::new([0u8; CRYPTO_KEYS_SIZE]);
+        for (n, key) in [
+            &self.chunk_hash_key,
+            &self.chunk_enc_key,
+            &self.inode_hash_key,
+            &self.inode_enc_key,
+            &self.backup_enc_key,
+            &self.manifest_sig_key,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            bytes[n * KEY_SIZE..(n + 1) * KEY_SIZE].copy_from_slice(key.as_array());
+        }
+        bytes
+    }
+}
+
+impl From<&[u8; CRYPTO_KEYS_SIZE]> for CryptoKeys {
+    fn from(keys: &[u8; CRYPTO_KEYS_SIZE]) -> Self {
         let mut n = KEY_SIZE;
         let chunk_hash_key = Key256::try_from(&keys[n - KEY_SIZE..n])
             .expect("This can not fail because we take care of the correct size here");
@@ -289,24 +844,227 @@ impl CryptoKeys {
         n += KEY_SIZE;
         let inode_enc_key = Key256::try_from(&keys[n - KEY_SIZE..n])
             .expect("This can not fail because we take care of the correct size here");
+        n += KEY_SIZE;
+        let backup_enc_key = Key256::try_from(&keys[n - KEY_SIZE..n])
+            .expect("This can not fail because we take care of the correct size here");
+        n += KEY_SIZE;
+        let manifest_sig_key = Key256::try_from(&keys[n - KEY_SIZE..n])
+            .expect("This can not fail because we take care of the correct size here");
 
         CryptoKeys {
             chunk_hash_key,
             chunk_enc_key,
             inode_hash_key,
             inode_enc_key,
+            backup_enc_key,
+            manifest_sig_key,
         }
     }
 }
 
+impl Encrypt for CryptoKeys {}
+
+impl Encrypt for Key256 {}
+
 impl CryptoKeys {
-    pub fn encrypt(&self, keys: KeyEncryptionKeys) -> EncCryptoKeys {
+    /// Identifies the repository without revealing anything about its keys
+    ///
+    /// Write-only clients compute the same id, see [`WriteOnlyKeys::repository_id`].
+    pub fn repository_id(&self) -> Hash256 {
+        repository_id(&write_only_manifest_key(&self.manifest_sig_key))
+    }
+}
+
+impl Zeroize for CryptoKeys {
+    fn zeroize(&mut self) {
+        self.chunk_hash_key.zeroize();
+        self.chunk_enc_key.zeroize();
+        self.inode_hash_key.zeroize();
+        self.inode_enc_key.zeroize();
+        self.backup_enc_key.zeroize();
+        self.manifest_sig_key.zeroize();
+    }
+}
+
+impl CryptoKeys {
+    pub fn xor_wrap(&self, keys: &KeyEncryptionKeys) -> EncCryptoKeys {
         EncCryptoKeys {
             enc_chunk_hash_key: self.chunk_hash_key.xor_keys(&keys.key_chunk_hash_key),
             enc_chunk_enc_key: self.chunk_enc_key.xor_keys(&keys.key_chunk_enc_key),
             enc_inode_hash_key: self.inode_hash_key.xor_keys(&keys.key_inode_hash_key),
             enc_inode_enc_key: self.inode_enc_key.xor_keys(&keys.key_inode_enc_key),
+            enc_backup_enc_key: self.backup_enc_key.xor_keys(&keys.key_backup_enc_key),
+            enc_manifest_sig_key: self.manifest_sig_key.xor_keys(&keys.key_manifest_sig_key),
+        }
+    }
+}
+
+impl CryptoKeys {
+    /// Wraps the data keys like manifests without key slots did
+    pub fn xor_wrap_v0(&self, keys: &KeyEncryptionKeys) -> EncCryptoKeysV0 {
+        EncCryptoKeysV0 {
+            enc_chunk_hash_key: self.chunk_hash_key.xor_keys(&keys.key_chunk_hash_key),
+            enc_chunk_enc_key: self.chunk_enc_key.xor_keys(&keys.key_chunk_enc_key),
+            enc_inode_hash_key: self.inode_hash_key.xor_keys(&keys.key_inode_hash_key),
+            enc_inode_enc_key: self.inode_enc_key.xor_keys(&keys.key_inode_enc_key),
+        }
+    }
+}
+
+/// Key pair of a repository in write-only mode, stored in the [`Manifest`]
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RepositoryKeyPair {
+    pub public_key: PublicKey256,
+    /// X25519 secret key encrypted with the backup encryption key of [`CryptoKeys`]
+    pub secret_key: Vec<u8>,
+}
+
+impl CanonicalEncode for RepositoryKeyPair {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        self.public_key.encode_canonical(out);
+        self.secret_key.encode_canonical(out);
+    }
+}
+
+impl RepositoryKeyPair {
+    /// Generates a new key pair whose secret key is protected by `keys`
+    pub fn generate(keys: &CryptoKeys) -> Result<Self> {
+        let mut secret_key = Key256([0u8; KEY_SIZE]);
+        OsRng.fill_bytes(&mut secret_key.0);
+        Self::from_secret_key(keys, &secret_key)
+    }
+
+    /// Restores the key pair of `secret_key`, protected by `keys`
+    pub fn from_secret_key(keys: &CryptoKeys, secret_key: &Key256) -> Result<Self> {
+        let public_key = PublicKey256::from_secret_key(secret_key);
+        Ok(RepositoryKeyPair {
+            public_key,
+            secret_key: secret_key
+                .encrypt_with_aad(&keys.backup_enc_key, &Self::associated_data(&public_key))?,
+        })
+    }
+
+    /// Decrypts the secret key and checks that it belongs to the public key
+    pub fn secret_key(&self, keys: &CryptoKeys) -> Result<Key256> {
+        let secret_key = Key256::decrypt_with_aad(
+            &self.secret_key,
+            &keys.backup_enc_key,
+            &Self::associated_data(&self.public_key),
+        )?;
+        if PublicKey256::from_secret_key(&secret_key) != self.public_key {
+            return Err(BackrubError::KeyPairMismatch.into());
         }
+        Ok(secret_key)
+    }
+
+    fn associated_data(public_key: &PublicKey256) -> Vec<u8> {
+        associated_data(
+            ObjectType::RepositorySecretKey,
+            &Hash256::from(*public_key.as_array()),
+        )
+    }
+}
+
+/// Keys that protect the content of a write-only repository
+///
+/// Write-only clients only know the public key, so they can add content but not read it.
+#[derive(Clone, Debug)]
+pub struct SealingKeys {
+    pub(crate) public_key: PublicKey256,
+    pub(crate) secret_key: Option<Locked<Key256>>,
+}
+
+impl SealingKeys {
+    pub fn new(public_key: PublicKey256, secret_key: Option<Key256>) -> Self {
+        SealingKeys {
+            public_key,
+            secret_key: secret_key.map(Locked::new),
+        }
+    }
+
+    /// Returns `true` if the content can be decrypted
+    pub fn can_open(&self) -> bool {
+        self.secret_key.is_some()
+    }
+
+    /// Returns the secret key or [`BackrubError::WriteOnlyAccess`] on write-only clients
+    pub fn secret_key(&self) -> Result<&Key256> {
+        match &self.secret_key {
+            Some(secret_key) => Ok(secret_key),
+            None => Err(BackrubError::WriteOnlyAccess.into()),
+        }
+    }
+}
+
+/// Keys of a write-only client, see [`BackupManager::export_write_only_keys`](crate::manager::BackupManager::export_write_only_keys)
+///
+/// They are enough to add backups to a repository, but not to read, restore or delete them.
+/// A write-only client never writes the manifest, but write-only clients trust each other's
+/// manifest signatures, see [`write_only_manifest_key`].
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WriteOnlyKeys {
+    pub(crate) chunk_hash_key: Key256,
+    pub(crate) chunk_enc_key: Key256,
+    pub(crate) inode_hash_key: Key256,
+    pub(crate) inode_enc_key: Key256,
+    /// See [`write_only_manifest_key`]
+    pub(crate) manifest_verify_key: Key256,
+    pub(crate) public_key: PublicKey256,
+}
+
+impl WriteOnlyKeys {
+    pub fn new(keys: &CryptoKeys, public_key: PublicKey256) -> Self {
+        WriteOnlyKeys {
+            chunk_hash_key: keys.chunk_hash_key.clone(),
+            chunk_enc_key: keys.chunk_enc_key.clone(),
+            inode_hash_key: keys.inode_hash_key.clone(),
+            inode_enc_key: keys.inode_enc_key.clone(),
+            manifest_verify_key: write_only_manifest_key(&keys.manifest_sig_key),
+            public_key,
+        }
+    }
+
+    /// The [`CryptoKeys`] of a write-only client
+    ///
+    /// The backup encryption and manifest signature keys are unknown to write-only clients,
+    /// random keys take their place. They protect nothing, backup records and the secret key are
+    /// only readable by key slots and manifests are verified with [`Self::manifest_verify_key`].
+    pub fn crypto_keys(&self) -> CryptoKeys {
+        let random = CryptoKeys::new();
+        CryptoKeys {
+            chunk_hash_key: self.chunk_hash_key.clone(),
+            chunk_enc_key: self.chunk_enc_key.clone(),
+            inode_hash_key: self.inode_hash_key.clone(),
+            inode_enc_key: self.inode_enc_key.clone(),
+            backup_enc_key: random.backup_enc_key.clone(),
+            manifest_sig_key: random.manifest_sig_key.clone(),
+        }
+    }
+
+    pub fn manifest_verify_key(&self) -> &Key256 {
+        &self.manifest_verify_key
+    }
+
+    /// Identifies the repository like [`CryptoKeys::repository_id`] does for full clients
+    ///
+    /// The id can not be taken from [`Self::crypto_keys`], their manifest signature key is random.
+    pub fn repository_id(&self) -> Hash256 {
+        repository_id(&self.manifest_verify_key)
+    }
+
+    /// Writes the keys to `path`, readable only by the owner
+    ///
+    /// Fails if the file already exists.
+    pub fn write_to_file(&self, path: &Path) -> Result<()> {
+        let json = Zeroizing::new(serde_json::to_string(self)?);
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
     }
 }
 
@@ -319,11 +1077,21 @@ pub struct Argon2Conf {
     pub version: u32,
 }
 
+impl CanonicalEncode for Argon2Conf {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        self.threads.encode_canonical(out);
+        self.mem_cost.encode_canonical(out);
+        self.time_cost.encode_canonical(out);
+        self.variant.encode_canonical(out);
+        self.version.encode_canonical(out);
+    }
+}
+
 impl Default for Argon2Conf {
     fn default() -> Self {
         Argon2Conf {
             threads: 4,
-            mem_cost: 1024 * 1024 * 2, // 2 GB per thread => 8GB total
+            mem_cost: 1024 * 1024 * 2, // 2 GiB shared by all threads
             time_cost: 20,             // very conservative value
             variant: argon2::Variant::Argon2id.as_u32(),
             version: argon2::Version::Version13.as_u32(),
@@ -332,7 +1100,73 @@ impl Default for Argon2Conf {
 }
 
 impl Argon2Conf {
+    /// Benchmarks Argon2 on this machine and returns parameters that take about `target_duration`
+    ///
+    /// Up to 4 threads are used. Memory is preferred over iterations, so the memory cost is
+    /// `max_memory` bytes unless a single pass over it already takes longer than
+    /// `target_duration`.
+    pub fn calibrate(target_duration: Duration, max_memory: u64) -> Result<Argon2Conf> {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get().min(4)) as u32;
+        let mut conf = Argon2Conf {
+            threads,
+            mem_cost: u32::try_from(max_memory / 1024).unwrap_or(u32::MAX),
+            time_cost: 1,
+            ..Argon2Conf::default()
+        };
+        conf.validate()?;
+
+        let elapsed = conf.benchmark()?.as_secs_f64().max(f64::MIN_POSITIVE);
+        let scale = target_duration.as_secs_f64() / elapsed;
+        if scale < 1.0 {
+            // the duration grows about linearly with the memory cost
+            conf.mem_cost = ((conf.mem_cost as f64 * scale) as u32).max(8 * threads);
+        } else {
+            conf.time_cost = scale as u32;
+        }
+
+        Ok(conf)
+    }
+
+    /// Measures how long deriving keys with this configuration takes
+    pub fn benchmark(&self) -> Result<Duration> {
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+
+        let start = Instant::now();
+        argon2::hash_raw(b"backrub calibration", &salt, &self.as_argon2config()?)?;
+        Ok(start.elapsed())
+    }
+
+    /// Checks the parameters, so invalid ones are reported before any key is derived
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(BackrubError::InvalidArgon2Conf(reason).into());
+        if self.threads == 0 || self.threads > 0x00FF_FFFF {
+            return invalid(format!(
+                "threads must be between 1 and {}, not {}",
+                0x00FF_FFFF, self.threads
+            ));
+        }
+        if self.mem_cost < 8 * self.threads {
+            return invalid(format!(
+                "mem_cost must be at least 8 KiB per thread ({} KiB), not {} KiB",
+                8 * self.threads,
+                self.mem_cost
+            ));
+        }
+        if self.time_cost == 0 {
+            return invalid("time_cost must be at least 1".to_string());
+        }
+        if argon2::Variant::from_u32(self.variant).is_err() {
+            return invalid(format!("unknown variant {}", self.variant));
+        }
+        if argon2::Version::from_u32(self.version).is_err() {
+            return invalid(format!("unknown version {:#x}", self.version));
+        }
+        Ok(())
+    }
+
     pub fn as_argon2config(&self) -> Result<argon2::Config> {
+        self.validate()?;
         Ok(argon2::Config {
             ad: &[],
             hash_length: TOTAL_KEY_SIZE as u32,
@@ -351,60 +1185,351 @@ impl Argon2Conf {
 pub struct SignedManifest {
     pub manifest: Manifest,
     pub signature: [u8; 32],
+    /// Signature under [`write_only_manifest_key`], which write-only clients verify instead
+    ///
+    /// Full clients only trust [`Self::signature`], so whoever holds write-only keys can not
+    /// make them accept a manifest.
+    #[serde(default)]
+    pub write_only_signature: Option<[u8; 32]>,
 }
 
 impl SignedManifest {
     pub fn verify(&self, key: &Key256) -> Result<Manifest> {
-        let self_ = (*self).clone();
-        let verify = *self.manifest.keyed_hash(key)?.as_bytes();
-        if verify != self_.signature {
+        // comparing blake3::Hash values is constant-time
+        if self.manifest.signature(key)? != blake3::Hash::from(self.signature) {
             Err(BackrubError::InvalidSignature.into())
         } else {
-            Ok(self_.manifest)
+            Ok(self.manifest.clone())
         }
     }
-    pub fn get_salt(&self) -> [u8; SALT_SIZE] {
-        self.manifest.salt
+
+    /// Verifies the manifest with the key of [`WriteOnlyKeys`]
+    ///
+    /// Returns [`BackrubError::WriteOnlyAccess`] if the manifest has no write-only signature yet,
+    /// it is added when a full client migrates the repository.
+    pub fn verify_write_only(&self, key: &Key256) -> Result<Manifest> {
+        let signature = self
+            .write_only_signature
+            .ok_or(BackrubError::WriteOnlyAccess)?;
+        // comparing blake3::Hash values is constant-time
+        if self.manifest.signature(key)? != blake3::Hash::from(signature) {
+            Err(BackrubError::InvalidSignature.into())
+        } else {
+            Ok(self.manifest.clone())
+        }
     }
+}
+
+/// Key write-only clients verify manifests with, it is derived from the manifest signature key
+///
+/// The signature key can not be computed from it, so full clients never accept a manifest signed
+/// by a write-only client. The key is symmetric though, whoever holds [`WriteOnlyKeys`] can
+/// produce a [`SignedManifest::write_only_signature`] that all write-only clients accept.
+pub fn write_only_manifest_key(manifest_sig_key: &Key256) -> Key256 {
+    Key256::from(blake3::derive_key(
+        WRITE_ONLY_MANIFEST_CONTEXT,
+        manifest_sig_key.as_array(),
+    ))
+}
+
+/// Derives the repository id from the key full and write-only clients verify manifests with
+fn repository_id(manifest_verify_key: &Key256) -> Hash256 {
+    Hash256::from(blake3::derive_key(
+        REPOSITORY_ID_CONTEXT,
+        manifest_verify_key.as_array(),
+    ))
+}
 
-    pub fn get_argon2config(&self) -> Result<argon2::Config> {
-        self.manifest.argon2_conf.as_argon2config()
+/// Wraps the [`CryptoKeys`] of a repository under keys derived from one password
+///
+/// A repository can have several key slots, every one of them unlocks the same keys.
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeySlot {
+    pub label: String,
+    pub salt: [u8; SALT_SIZE],
+    /// `None` for slots that are unlocked by a random key instead of a password
+    pub argon2_conf: Option<Argon2Conf>,
+    /// [`CryptoKeys`] encrypted with [`KeyEncryptionKeys::wrapping_key`]
+    pub keys: Vec<u8>,
+}
+
+impl CanonicalEncode for KeySlot {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        self.label.encode_canonical(out);
+        self.salt.encode_canonical(out);
+        self.argon2_conf.encode_canonical(out);
+        self.keys.encode_canonical(out);
     }
 }
 
 #[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Manifest {
-    pub salt: [u8; SALT_SIZE],
+    pub format_version: u32,
+    /// Root directory of [`StorageConf::Local`]
     pub chunk_root_dir: PathBuf,
     pub db_path: PathBuf,
     pub version: String,
     pub chunker_conf: ChunkerConf,
-    pub keys: EncCryptoKeys,
+    pub key_slots: Vec<KeySlot>,
     //completed_backups: BTreeMap<BackupHash256,Vec<u8>>,
-    pub argon2_conf: Argon2Conf,
     pub chunk_db_state: ChunkDbState,
+    /// Incremented every time the manifest is written, so a client notices an older copy
+    #[serde(default)]
+    pub generation: u64,
+    /// [`Manifest::digest`] of the manifest this one replaced, zero for a new repository
+    #[serde(default)]
+    pub previous_manifest_hash: Hash256,
+    /// Key pair the content is sealed to, `None` unless the repository was created write-only
+    #[serde(default)]
+    pub key_pair: Option<RepositoryKeyPair>,
+    /// Compression of new chunks
+    #[serde(default)]
+    pub compression: Compression,
+    /// Size at which a pack file is finished, `0` stores every new chunk in a file of its own
+    #[serde(default)]
+    pub max_pack_size: u64,
+    /// Where the chunk data is stored
+    #[serde(default)]
+    pub storage: StorageConf,
 }
 
 impl Hashable for Manifest {}
 
+impl CanonicalEncode for Manifest {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        self.format_version.encode_canonical(out);
+        self.chunk_root_dir.encode_canonical(out);
+        self.db_path.encode_canonical(out);
+        self.version.encode_canonical(out);
+        self.chunker_conf.encode_canonical(out);
+        self.key_slots.encode_canonical(out);
+        self.chunk_db_state.encode_canonical(out);
+        if self.format_version >= GENERATION_FORMAT_VERSION {
+            self.generation.encode_canonical(out);
+            self.previous_manifest_hash.encode_canonical(out);
+        }
+        if self.format_version >= KEY_PAIR_FORMAT_VERSION {
+            self.key_pair.encode_canonical(out);
+        }
+        if self.format_version >= COMPRESSION_FORMAT_VERSION {
+            self.compression.encode_canonical(out);
+        }
+        if self.format_version >= PACK_FORMAT_VERSION {
+            self.max_pack_size.encode_canonical(out);
+        }
+        if self.format_version >= STORAGE_FORMAT_VERSION {
+            self.storage.encode_canonical(out);
+        }
+    }
+}
+
+/// Manifest written before key slots existed, it has no format version
+///
+/// It is signed with the first [`KEY_SIZE`] bytes of the Argon2 output of its password,
+/// the rest of the output unwraps [`Self::keys`].
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ManifestV0 {
+    pub salt: [u8; SALT_SIZE],
+    pub chunk_root_dir: PathBuf,
+    pub db_path: PathBuf,
+    pub version: String,
+    pub chunker_conf: ChunkerConf,
+    pub keys: EncCryptoKeysV0,
+    pub argon2_conf: Argon2Conf,
+    pub chunk_db_state: ChunkDbState,
+}
+
+impl Hashable for ManifestV0 {}
+
+impl ManifestV0 {
+    pub fn sign(&self, key: &Key256) -> Result<SignedManifestV0> {
+        Ok(SignedManifestV0 {
+            manifest: self.clone(),
+            signature: *self.keyed_hash(key)?.as_bytes(),
+        })
+    }
+}
+
+#[derive(Clone, Hash, Debug, Serialize, Deserialize)]
+pub struct SignedManifestV0 {
+    pub manifest: ManifestV0,
+    pub signature: [u8; 32],
+}
+
+impl SignedManifestV0 {
+    pub fn verify(&self, key: &Key256) -> Result<ManifestV0> {
+        // comparing blake3::Hash values is constant-time
+        if self.manifest.keyed_hash(key)? != blake3::Hash::from(self.signature) {
+            Err(BackrubError::InvalidSignature.into())
+        } else {
+            Ok(self.manifest.clone())
+        }
+    }
+}
+
+/// Key slot of manifest format version 1
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeySlotV1 {
+    pub label: String,
+    pub salt: [u8; SALT_SIZE],
+    pub argon2_conf: Argon2Conf,
+    pub keys: EncCryptoKeys,
+    /// Keyed hash of the salt, used to find the slot matching a password
+    pub verifier: Hash256,
+}
+
+/// Manifest format version 1, only read to migrate old repositories
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ManifestV1 {
+    pub chunk_root_dir: PathBuf,
+    pub db_path: PathBuf,
+    pub version: String,
+    pub chunker_conf: ChunkerConf,
+    pub key_slots: Vec<KeySlotV1>,
+    pub chunk_db_state: ChunkDbState,
+}
+
+impl Hashable for ManifestV1 {}
+
+#[derive(Clone, Hash, Debug, Serialize, Deserialize)]
+pub struct SignedManifestV1 {
+    pub manifest: ManifestV1,
+    pub signature: [u8; 32],
+}
+
+impl SignedManifestV1 {
+    pub fn verify(&self, key: &Key256) -> Result<ManifestV1> {
+        // comparing blake3::Hash values is constant-time
+        if self.manifest.keyed_hash(key)? != blake3::Hash::from(self.signature) {
+            Err(BackrubError::InvalidSignature.into())
+        } else {
+            Ok(self.manifest.clone())
+        }
+    }
+}
+
 impl Manifest {
     //        pub fn new()
 
+    /// Signs the manifest with the manifest signature key, for write-only clients as well
     pub fn sign(&self, key: &Key256) -> Result<SignedManifest> {
         let manifest = (*self).clone();
-        let signature = *self.keyed_hash(key)?.as_bytes();
+        let signature = *self.signature(key)?.as_bytes();
+        let write_only_signature = match self.format_version >= WRITE_ONLY_SIGNATURE_FORMAT_VERSION
+        {
+            true => Some(*self.signature(&write_only_manifest_key(key))?.as_bytes()),
+            false => None,
+        };
         Ok(SignedManifest {
             manifest,
             signature,
+            write_only_signature,
         })
     }
+
+    /// The bytes covered by the signature, a [`CanonicalEncode`] encoding of the manifest
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(b"backrub manifest");
+        MANIFEST_SIGNATURE_VERSION.encode_canonical(&mut out);
+        self.encode_canonical(&mut out);
+        out
+    }
+
+    /// Hash identifying this manifest, chained into its successor
+    pub fn digest(&self) -> Hash256 {
+        Hash256::from(*blake3::hash(&self.signed_bytes()).as_bytes())
+    }
+
+    /// Computes the signature of the manifest with the scheme of its format version
+    ///
+    /// Since [`CANONICAL_SIGNATURE_FORMAT_VERSION`] this is a keyed BLAKE3 hash of
+    /// [`Self::signed_bytes`] under a key derived from `key` for manifests only, so it can never
+    /// equal a chunk or inode hash. Older manifests are signed over their bincode serialization.
+    fn signature(&self, key: &Key256) -> Result<blake3::Hash> {
+        if self.format_version < CANONICAL_SIGNATURE_FORMAT_VERSION {
+            return self.keyed_hash(key);
+        }
+        let signature_key = Zeroizing::new(blake3::derive_key(
+            MANIFEST_SIGNATURE_CONTEXT,
+            key.as_array(),
+        ));
+        Ok(blake3::keyed_hash(&signature_key, &self.signed_bytes()))
+    }
+}
+
+/// Summary statistics of a backup run
+#[derive(Clone, Copy, Hash, Default, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BackupStats {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// Total size of all backed up files
+    pub bytes: u64,
+    /// Number of chunks that were not stored in the repository before
+    pub new_chunks: u64,
+    /// Size of the new chunks after compression and encryption
+    pub new_chunk_bytes: u64,
+    /// Number of missing or corrupt chunk files that were rewritten in repair mode
+    pub repaired_chunks: u64,
+    /// Number of new chunks stored uncompressed because compressing them saved too little
+    pub uncompressed_chunks: u64,
+    /// Number of files that were read and chunked
+    pub files_read: u64,
+    /// Number of unchanged files that were taken from the files cache
+    pub files_skipped: u64,
 }
 
 #[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Backup {
+    pub(crate) id: Hash256,
     pub(crate) timestamp: String,
     pub(crate) name: String,
     pub(crate) root: Hash256,
+    pub(crate) finished: bool,
+    pub(crate) source_host: String,
+    pub(crate) source_path: PathBuf,
+    pub(crate) stats: BackupStats,
+}
+
+impl Encrypt for Backup {}
+
+impl Backup {
+    /// Random id the backup is stored under
+    pub fn id(&self) -> &Hash256 {
+        &self.id
+    }
+
+    /// Start time of the backup as RFC 3339 string
+    pub fn timestamp(&self) -> &str {
+        &self.timestamp
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Key of the root [`Directory`] inode, only valid if the backup is finished
+    pub fn root(&self) -> &Hash256 {
+        &self.root
+    }
+
+    /// `false` if the backup run was interrupted
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn source_host(&self) -> &str {
+        &self.source_host
+    }
+
+    pub fn source_path(&self) -> &Path {
+        &self.source_path
+    }
+
+    pub fn stats(&self) -> &BackupStats {
+        &self.stats
+    }
 }
 
 #[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -436,8 +1561,20 @@ pub enum Inode {
     Symlink(Symlink),
 }
 
+impl Encrypt for Inode {}
 impl Hashable for Inode {}
 
+impl Inode {
+    /// Path of the inode relative to the backup root
+    pub fn relpath(&self) -> &Path {
+        match self {
+            Inode::File(file) => &file.relpath,
+            Inode::Directory(dir) => &dir.relpath,
+            Inode::Symlink(link) => &link.relpath,
+        }
+    }
+}
+
 #[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Chunk {
     pub data: Vec<u8>,
@@ -449,6 +1586,35 @@ impl Hashable for Chunk {}
 #[derive(Clone, Hash, Default, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct FilePathGen(pub(crate) u64);
 
+impl CanonicalEncode for FilePathGen {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        self.0.encode_canonical(out);
+    }
+}
+
+impl FilePathGen {
+    /// Returns the counter value after generating `path`, `None` if it is no generated path
+    pub fn position(path: &Path) -> Option<u64> {
+        let path = path.to_str()?;
+        let (folders, file_name) = match path.rsplit_once('/') {
+            Some((folders, file_name)) => (Some(folders), file_name),
+            None => (None, path),
+        };
+
+        let mut value = u64::from_str_radix(file_name.strip_suffix(".bin")?, 16).ok()?;
+        for (i, folder) in folders.into_iter().flat_map(|f| f.split('/')).enumerate() {
+            if i >= 7 {
+                return None;
+            }
+            value |= u64::from_str_radix(folder, 16).ok()? << (8 * (i + 1));
+        }
+
+        // generating the path again rejects everything that merely parses
+        let mut path_gen = FilePathGen(value.checked_sub(1)?);
+        (path_gen.next()? == path).then_some(value)
+    }
+}
+
 impl From<u64> for FilePathGen {
     fn from(value: u64) -> Self {
         FilePathGen { 0: value }
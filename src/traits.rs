@@ -1,11 +1,10 @@
+use bincode::Options;
 use chacha20poly1305::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
     XChaCha20Poly1305,
 };
-use flate2::write::{DeflateDecoder, DeflateEncoder};
-use flate2::Compression;
 use serde::{Deserialize, Serialize};
-use std::io::prelude::*;
+use std::{os::unix::ffi::OsStrExt, path::PathBuf};
 
 use super::error::*;
 use super::structs::*;
@@ -14,6 +13,84 @@ use super::structs::*;
 struct CryptoCtx {
     nonce: Nonce192,
     data: Vec<u8>,
+    /// Compression of the encrypted data, authenticated along with the associated data
+    compression: Option<Compression>,
+}
+
+/// [`CryptoCtx`] written before the compression was recorded, compressed data used deflate
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct LegacyCryptoCtx {
+    nonce: Nonce192,
+    data: Vec<u8>,
+}
+
+impl CryptoCtx {
+    fn decode(data: &[u8]) -> Result<Self> {
+        // like bincode::deserialize, but a legacy context must not decode from a current one
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes();
+
+        // legacy contexts end right after the data, so they never decode as current ones
+        match options.deserialize::<CryptoCtx>(data) {
+            Ok(ctx) => Ok(ctx),
+            Err(_) => {
+                let legacy = options.deserialize::<LegacyCryptoCtx>(data)?;
+                Ok(CryptoCtx {
+                    nonce: legacy.nonce,
+                    data: legacy.data,
+                    compression: None,
+                })
+            }
+        }
+    }
+
+    /// Associated data of the cipher, which includes the recorded compression
+    fn cipher_aad(&self, aad: &[u8]) -> Vec<u8> {
+        let mut cipher_aad = aad.to_vec();
+        if let Some(compression) = &self.compression {
+            compression.encode_canonical(&mut cipher_aad);
+        }
+        cipher_aad
+    }
+}
+
+/// A [`CryptoCtx`] encrypted with a key agreed between an ephemeral and a repository key pair
+#[derive(Clone, Hash, Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct SealedCtx {
+    ephemeral_public_key: PublicKey256,
+    ctx: Vec<u8>,
+}
+
+/// Version of the associated data layout built by [`associated_data`]
+pub const AAD_FORMAT_VERSION: u32 = 1;
+
+/// Kinds of encrypted objects, bound into the associated data of their ciphertexts
+#[derive(Clone, Copy, Hash, Debug, PartialEq, Eq)]
+pub enum ObjectType {
+    Chunk = 1,
+    ChunkDbEntry = 2,
+    // 3 belonged to the entries of a removed generic reference counting database
+    InodeDbEntry = 4,
+    Backup = 5,
+    FilesCacheEntry = 6,
+    /// Inode sealed into an inode database entry of a write-only repository
+    SealedInode = 7,
+    RepositorySecretKey = 8,
+    /// Index at the end of a pack file, its id is [`PackIndex::pack_id`](crate::pack::PackIndex::pack_id)
+    PackIndex = 9,
+}
+
+/// Builds the associated data binding a ciphertext to the type and id of the object it holds
+///
+/// A ciphertext that is moved to another id or object type fails authentication.
+pub fn associated_data(object_type: ObjectType, id: &Hash256) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(7 + 4 + 1 + HASH_SIZE);
+    aad.extend_from_slice(b"backrub");
+    aad.extend_from_slice(&AAD_FORMAT_VERSION.to_le_bytes());
+    aad.push(object_type as u8);
+    aad.extend_from_slice(id.as_ref());
+    aad
 }
 
 /// Generic trait for cryptographically hashing all structs that implement Serialize
@@ -75,6 +152,83 @@ impl Hashable for &[u8] {
     }
 }
 
+/// Stable binary encoding for data that is authenticated, independent of the serde layout
+///
+/// Integers are little endian with a fixed width, sequences are prefixed with their length as
+/// [`u64`] and options with a `0` or `1` byte. Fields of structs are encoded in declaration
+/// order, so adding, removing or reordering fields changes the encoding and needs a new version.
+pub trait CanonicalEncode {
+    fn encode_canonical(&self, out: &mut Vec<u8>);
+}
+
+impl CanonicalEncode for u8 {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+}
+
+impl CanonicalEncode for u32 {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl CanonicalEncode for u64 {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl CanonicalEncode for [u8] {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).encode_canonical(out);
+        out.extend_from_slice(self);
+    }
+}
+
+/// Arrays have a fixed length, so it is not encoded
+impl<T: CanonicalEncode, const N: usize> CanonicalEncode for [T; N] {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        for item in self {
+            item.encode_canonical(out);
+        }
+    }
+}
+
+impl<T: CanonicalEncode> CanonicalEncode for Vec<T> {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).encode_canonical(out);
+        for item in self {
+            item.encode_canonical(out);
+        }
+    }
+}
+
+impl<T: CanonicalEncode> CanonicalEncode for Option<T> {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        match self {
+            None => 0u8.encode_canonical(out),
+            Some(value) => {
+                1u8.encode_canonical(out);
+                value.encode_canonical(out);
+            }
+        }
+    }
+}
+
+impl CanonicalEncode for String {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        self.as_bytes().encode_canonical(out);
+    }
+}
+
+/// Paths are encoded as their raw bytes
+impl CanonicalEncode for PathBuf {
+    fn encode_canonical(&self, out: &mut Vec<u8>) {
+        self.as_os_str().as_bytes().encode_canonical(out);
+    }
+}
+
 /// Generic trait for symmetric encrpytion for all structs that implement Serialize and Deserialize
 /// ```rust
 /// use serde::{Deserialize, Serialize};
@@ -90,93 +244,266 @@ impl Hashable for &[u8] {
 pub trait Encrypt: Serialize + for<'a> Deserialize<'a> {
     /// Generic function to encrypt data in backrub
     fn encrypt(&self, key: &Key256) -> Result<Vec<u8>> {
-        // convert data to Vec<u8>
-        let serialized_data = bincode::serialize(self)?;
-        serialized_data.encrypt(key)
+        self.encrypt_with_aad(key, &[])
     }
 
     /// Generic function to decrypt data encrypted by backrub
     fn decrypt(data: &[u8], key: &Key256) -> Result<Self> {
+        Self::decrypt_with_aad(data, key, &[])
+    }
+
+    /// Generic function to compress and encrypt data in backrub
+    fn compress_and_encrypt(&self, key: &Key256) -> Result<Vec<u8>> {
+        self.compress_and_encrypt_with_aad(key, &[])
+    }
+
+    /// Generic function to decrypt and uncompress data encrypted by backrub
+    fn decrypt_and_uncompress(data: &[u8], key: &Key256) -> Result<Self> {
+        Self::decrypt_and_uncompress_with_aad(data, key, &[])
+    }
+
+    /// Encrypts data and authenticates the associated data `aad` along with it
+    fn encrypt_with_aad(&self, key: &Key256, aad: &[u8]) -> Result<Vec<u8>> {
+        // convert data to Vec<u8>
+        let serialized_data = bincode::serialize(self)?;
+        serialized_data.encrypt_with_aad(key, aad)
+    }
+
+    /// Decrypts data encrypted by [`Encrypt::encrypt_with_aad`] with the same associated data
+    fn decrypt_with_aad(data: &[u8], key: &Key256, aad: &[u8]) -> Result<Self> {
         // decrypt the data
-        let data = Vec::<u8>::decrypt(data, key)?;
+        let data = Vec::<u8>::decrypt_with_aad(data, key, aad)?;
         // convert decrypted data to the target data type
         Ok(bincode::deserialize(&data)?)
     }
 
-    /// Generic function to compress and encrypt data in backrub
-    fn compress_and_encrypt(&self, key: &Key256) -> Result<Vec<u8>> {
+    /// Compresses and encrypts data and authenticates the associated data `aad` along with it
+    fn compress_and_encrypt_with_aad(&self, key: &Key256, aad: &[u8]) -> Result<Vec<u8>> {
+        self.compress_and_encrypt_with(Compression::default(), key, aad)
+    }
+
+    /// Like [`Encrypt::compress_and_encrypt_with_aad`] with the given compression
+    fn compress_and_encrypt_with(
+        &self,
+        compression: Compression,
+        key: &Key256,
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
         // convert data to Vec<u8>
         let serialized_data = bincode::serialize(self)?;
-        serialized_data.compress_and_encrypt(key)
+        serialized_data.compress_and_encrypt_with(compression, key, aad)
     }
 
-    /// Generic function to decrypt and uncompress data encrypted by backrub
-    fn decrypt_and_uncompress(data: &[u8], key: &Key256) -> Result<Self> {
+    /// Decrypts and uncompresses data encrypted by [`Encrypt::compress_and_encrypt_with_aad`]
+    /// with the same associated data
+    fn decrypt_and_uncompress_with_aad(data: &[u8], key: &Key256, aad: &[u8]) -> Result<Self> {
         // decrypt and decompress the data
-        let data = Vec::<u8>::decrypt_and_uncompress(data, key)?;
+        let data = Vec::<u8>::decrypt_and_uncompress_with_aad(data, key, aad)?;
         // deserialize uncompressed, decrypted data
         Ok(bincode::deserialize(&data)?)
     }
+
+    /// Encrypts data so that only the holder of the secret key of `public_key` can decrypt it
+    fn seal_with_aad(&self, public_key: &PublicKey256, aad: &[u8]) -> Result<Vec<u8>> {
+        let serialized_data = bincode::serialize(self)?;
+        seal(&serialized_data, public_key, aad, None)
+    }
+
+    /// Decrypts data sealed by [`Encrypt::seal_with_aad`] with the X25519 secret key
+    fn open_with_aad(data: &[u8], secret_key: &Key256, aad: &[u8]) -> Result<Self> {
+        let data = open(data, secret_key, aad, false)?;
+        Ok(bincode::deserialize(&data)?)
+    }
+
+    /// Compresses data and seals it like [`Encrypt::seal_with_aad`]
+    fn compress_and_seal_with_aad(&self, public_key: &PublicKey256, aad: &[u8]) -> Result<Vec<u8>> {
+        self.compress_and_seal_with(Compression::default(), public_key, aad)
+    }
+
+    /// Like [`Encrypt::compress_and_seal_with_aad`] with the given compression
+    fn compress_and_seal_with(
+        &self,
+        compression: Compression,
+        public_key: &PublicKey256,
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        let serialized_data = bincode::serialize(self)?;
+        seal(&serialized_data, public_key, aad, Some(compression))
+    }
+
+    /// Decrypts and uncompresses data sealed by [`Encrypt::compress_and_seal_with_aad`]
+    fn open_and_uncompress_with_aad(data: &[u8], secret_key: &Key256, aad: &[u8]) -> Result<Self> {
+        let data = open(data, secret_key, aad, true)?;
+        Ok(bincode::deserialize(&data)?)
+    }
+}
+
+/// Derives the symmetric key of a sealed box from the X25519 shared secret and both public keys
+fn sealing_key(
+    shared_secret: &x25519_dalek::SharedSecret,
+    ephemeral_public_key: &PublicKey256,
+    public_key: &PublicKey256,
+) -> Result<Key256> {
+    // an all zero shared secret means one of the public keys is of low order
+    if !shared_secret.was_contributory() {
+        return Err(chacha20poly1305::aead::Error.into());
+    }
+    let mut hasher = blake3::Hasher::new_derive_key("backrub sealed box v1");
+    hasher.update(shared_secret.as_bytes());
+    hasher.update(ephemeral_public_key.as_array());
+    hasher.update(public_key.as_array());
+    Ok(Key256::from(*hasher.finalize().as_bytes()))
+}
+
+/// Encrypts `data` with a fresh ephemeral key pair for `public_key`, like a libsodium sealed box
+fn seal(
+    data: &[u8],
+    public_key: &PublicKey256,
+    aad: &[u8],
+    compression: Option<Compression>,
+) -> Result<Vec<u8>> {
+    let ephemeral_secret = x25519_dalek::EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public_key =
+        PublicKey256::from(x25519_dalek::PublicKey::from(&ephemeral_secret).to_bytes());
+    let shared_secret =
+        ephemeral_secret.diffie_hellman(&x25519_dalek::PublicKey::from(*public_key.as_array()));
+    let key = sealing_key(&shared_secret, &ephemeral_public_key, public_key)?;
+
+    let data = data.to_vec();
+    let ctx = match compression {
+        Some(compression) => data.compress_and_encrypt_with(compression, &key, aad)?,
+        None => data.encrypt_with_aad(&key, aad)?,
+    };
+    Ok(bincode::serialize(&SealedCtx {
+        ephemeral_public_key,
+        ctx,
+    })?)
+}
+
+/// Decrypts data encrypted by [`seal`]
+fn open(data: &[u8], secret_key: &Key256, aad: &[u8], uncompress: bool) -> Result<Vec<u8>> {
+    let sealed = bincode::deserialize::<SealedCtx>(data)?;
+    let secret_key = x25519_dalek::StaticSecret::from(*secret_key.as_array());
+    let public_key = PublicKey256::from(x25519_dalek::PublicKey::from(&secret_key).to_bytes());
+    let shared_secret = secret_key.diffie_hellman(&x25519_dalek::PublicKey::from(
+        *sealed.ephemeral_public_key.as_array(),
+    ));
+    let key = sealing_key(&shared_secret, &sealed.ephemeral_public_key, &public_key)?;
+
+    if uncompress {
+        Vec::<u8>::decrypt_and_uncompress_with_aad(&sealed.ctx, &key, aad)
+    } else {
+        Vec::<u8>::decrypt_with_aad(&sealed.ctx, &key, aad)
+    }
 }
 
 impl Encrypt for Vec<u8> {
-    fn encrypt(&self, key: &Key256) -> Result<Vec<u8>> {
+    fn encrypt_with_aad(&self, key: &Key256, aad: &[u8]) -> Result<Vec<u8>> {
         // generate nonce
         let nonce: Nonce192 = XChaCha20Poly1305::generate_nonce(&mut OsRng).into();
         // setup the cipher
         let cipher = XChaCha20Poly1305::new(key.as_array().into());
         // encrypt the data
-        let encrypted_data = cipher.encrypt(nonce.as_array().into(), &self[..])?;
+        let encrypted_data = cipher.encrypt(
+            nonce.as_array().into(),
+            Payload {
+                msg: &self[..],
+                aad,
+            },
+        )?;
         // construct CryptoCtx using the nonce and the encrypted data
         let ctx = CryptoCtx {
             nonce,
             data: encrypted_data,
+            compression: None,
         };
         // convert CryptoCtx to Vec<u8>
         Ok(bincode::serialize(&ctx)?)
     }
 
-    fn decrypt(data: &[u8], key: &Key256) -> Result<Self> {
+    fn decrypt_with_aad(data: &[u8], key: &Key256, aad: &[u8]) -> Result<Self> {
         // decode encrypted data to split nonce and encrypted data
-        let ctx = bincode::deserialize::<CryptoCtx>(data)?;
+        let ctx = CryptoCtx::decode(data)?;
         // setup the cipher
         let cipher = XChaCha20Poly1305::new(key.as_array().into());
         // decrypt the data
-        Ok(cipher.decrypt(ctx.nonce.as_array().into(), &ctx.data[..])?)
+        Ok(cipher.decrypt(
+            ctx.nonce.as_array().into(),
+            Payload {
+                msg: &ctx.data[..],
+                aad: &ctx.cipher_aad(aad),
+            },
+        )?)
     }
 
-    fn compress_and_encrypt(&self, key: &Key256) -> Result<Vec<u8>> {
+    fn compress_and_encrypt_with(
+        &self,
+        compression: Compression,
+        key: &Key256,
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
         // generate nonce
         let nonce: Nonce192 = XChaCha20Poly1305::generate_nonce(&mut OsRng).into();
         // setup the cipher
         let cipher = XChaCha20Poly1305::new(key.as_array().into());
 
         // compress the data
-        let mut compressor = DeflateEncoder::new(Vec::new(), Compression::default());
-        compressor.write_all(&self[..])?;
-        let compressed_data = compressor.finish()?;
-        // encrypt the data
-        let encrypted_data = cipher.encrypt(nonce.as_array().into(), &compressed_data[..])?;
-        // construct CryptoCtx using the nonce and the encrypted data
-        let ctx = CryptoCtx {
+        let compressed_data = compression.compress(&self[..])?;
+        // construct CryptoCtx, the compression is authenticated with the data
+        let mut ctx = CryptoCtx {
             nonce,
-            data: encrypted_data,
+            data: Vec::new(),
+            compression: Some(compression),
         };
+        // encrypt the data
+        ctx.data = cipher.encrypt(
+            nonce.as_array().into(),
+            Payload {
+                msg: &compressed_data[..],
+                aad: &ctx.cipher_aad(aad),
+            },
+        )?;
         // convert CryptoCtx to Vec<u8>
         Ok(bincode::serialize(&ctx)?)
     }
 
-    fn decrypt_and_uncompress(data: &[u8], key: &Key256) -> Result<Self> {
+    fn decrypt_and_uncompress_with_aad(data: &[u8], key: &Key256, aad: &[u8]) -> Result<Self> {
         // decode encrypted data to split nonce and encrypted data
-        let ctx = bincode::deserialize::<CryptoCtx>(data)?;
+        let ctx = CryptoCtx::decode(data)?;
         // setup the cipher
         let cipher = XChaCha20Poly1305::new(key.as_array().into());
         // decrypt the data
-        let decrypted_data = cipher.decrypt(ctx.nonce.as_array().into(), &ctx.data[..])?;
-        // decompress decrypted data
-        let uncompressed_data = Vec::new();
-        let mut deflater = DeflateDecoder::new(uncompressed_data);
-        deflater.write_all(&decrypted_data)?;
-        Ok(deflater.finish()?)
+        let decrypted_data = cipher.decrypt(
+            ctx.nonce.as_array().into(),
+            Payload {
+                msg: &ctx.data[..],
+                aad: &ctx.cipher_aad(aad),
+            },
+        )?;
+        // decompress decrypted data, legacy data was always deflated
+        ctx.compression
+            .unwrap_or(Compression::Deflate)
+            .decompress(&decrypted_data)
+    }
+
+    fn seal_with_aad(&self, public_key: &PublicKey256, aad: &[u8]) -> Result<Vec<u8>> {
+        seal(self, public_key, aad, None)
+    }
+
+    fn open_with_aad(data: &[u8], secret_key: &Key256, aad: &[u8]) -> Result<Self> {
+        open(data, secret_key, aad, false)
+    }
+
+    fn compress_and_seal_with(
+        &self,
+        compression: Compression,
+        public_key: &PublicKey256,
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        seal(self, public_key, aad, Some(compression))
+    }
+
+    fn open_and_uncompress_with_aad(data: &[u8], secret_key: &Key256, aad: &[u8]) -> Result<Self> {
+        open(data, secret_key, aad, true)
     }
 }
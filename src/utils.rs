@@ -1,5 +1,6 @@
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
 use hash_roll::{fastcdc, gear_table::GEAR_64, ChunkIncr};
-use memmap::Mmap;
+use std::{fs, io::prelude::*, path::Path, time::Duration};
 
 use super::error::*;
 use super::structs::*;
@@ -13,11 +14,99 @@ pub fn log2u64(x: u64) -> Option<u64> {
     }
 }
 
-/// Calculate chunks, chunk hashes and a file-hash of mmaped data.
+/// Replaces the file at `path` with `data`, after a crash it is either the old or the new file
+///
+/// The data is written to a temporary sibling that is synced to disk and renamed over `path`,
+/// then the directory is synced so the rename is durable as well.
+/// The temporary name is unique, so concurrent writers of the same file do not collide,
+/// leftovers of crashed writers are removed by [`remove_stale_temp_files`].
+pub fn write_file_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut suffix = [0u8; 8];
+    OsRng.fill_bytes(&mut suffix);
+    let suffix: String = suffix.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(".{}.{}.tmp", std::process::id(), suffix));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let write = || -> Result<()> {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        Ok(())
+    };
+    if let Err(e) = write() {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    fs::rename(&tmp_path, path)?;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Temporary files that were not modified for this long belong to crashed writers
+pub const STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Whether `path` is a temporary file of [`write_file_atomic`], `<name>.<pid>.<suffix>.tmp`
+pub fn is_temp_file(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let mut parts = name.rsplitn(4, '.');
+    matches!(
+        (parts.next(), parts.next(), parts.next(), parts.next()),
+        (Some("tmp"), Some(suffix), Some(pid), Some(file_name))
+            if suffix.len() == 16
+                && suffix.bytes().all(|b| b.is_ascii_hexdigit())
+                && !pid.is_empty()
+                && pid.bytes().all(|b| b.is_ascii_digit())
+                && !file_name.is_empty()
+    )
+}
+
+/// Removes temporary files of [`write_file_atomic`] older than [`STALE_TEMP_FILE_AGE`]
+///
+/// Younger ones may still be written by another process, possibly on another host.
+/// Returns the number of removed files.
+pub fn remove_stale_temp_files(dir: &Path, recursive: bool) -> Result<usize> {
+    let mut walk = walkdir::WalkDir::new(dir).min_depth(1);
+    if !recursive {
+        walk = walk.max_depth(1);
+    }
+    let mut removed = 0;
+    for entry in walk {
+        let entry = match entry {
+            Err(e) if e.io_error().map(|e| e.kind()) == Some(std::io::ErrorKind::NotFound) => {
+                continue
+            }
+            entry => entry.map_err(std::io::Error::from)?,
+        };
+        if !entry.file_type().is_file() || !is_temp_file(entry.path()) {
+            continue;
+        }
+        let modified = entry.metadata().map_err(std::io::Error::from)?.modified()?;
+        if modified
+            .elapsed()
+            .is_ok_and(|age| age > STALE_TEMP_FILE_AGE)
+        {
+            match fs::remove_file(entry.path()) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => removed += 1,
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Calculate chunks, chunk hashes and a file-hash of (usually mmaped) data.
 /// Returns a [Vec] of `(Chunk, ChunkHash)` tuples and the FileHash.
 use std::sync::Arc;
 pub fn chunk_and_hash(
-    mmap: &Mmap,
+    data: &[u8],
     conf: &ChunkerConf,
     chunk_hash_key: &Key256,
     file_hash_key: &Key256,
@@ -31,7 +120,7 @@ pub fn chunk_and_hash(
     let chunk_iter = fastcdc::FastCdcIncr::from(&cdc);
 
     let chunks: Vec<(Arc<[u8]>, blake3::Hash)> = chunk_iter
-        .iter_slices(&mmap[..])
+        .iter_slices(data)
         .map(|chunk| {
             (
                 Arc::<[u8]>::from(chunk),
@@ -40,5 +129,5 @@ pub fn chunk_and_hash(
         })
         .collect();
 
-    Ok((chunks.into(), (&mmap[..]).keyed_hash(file_hash_key)?))
+    Ok((chunks.into(), data.keyed_hash(file_hash_key)?))
 }